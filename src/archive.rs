@@ -0,0 +1,310 @@
+use crate::sandbox;
+use anyhow::{bail, Context, Result};
+use nix::libc::stat;
+use nix::sys::stat::SFlag;
+use nix::unistd::{Group, User};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::{ptr, slice};
+
+// compress-tools does not expose link targets, xattrs or acls so read them from libarchive directly
+
+#[repr(C)]
+struct RawArchive {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct RawEntry {
+    _private: [u8; 0],
+}
+
+const ARCHIVE_OK: c_int = 0;
+const ARCHIVE_EOF: c_int = 1;
+const ARCHIVE_WARN: c_int = -20;
+
+const ACL_TYPE_ACCESS: c_int = 0x100;
+const ACL_TYPE_DEFAULT: c_int = 0x200;
+
+const ACL_USER: c_int = 10001;
+const ACL_USER_OBJ: c_int = 10002;
+const ACL_GROUP: c_int = 10003;
+const ACL_GROUP_OBJ: c_int = 10004;
+const ACL_MASK: c_int = 10005;
+const ACL_OTHER: c_int = 10006;
+
+#[link(name = "archive")]
+extern "C" {
+    fn archive_read_new() -> *mut RawArchive;
+    fn archive_read_support_filter_all(a: *mut RawArchive) -> c_int;
+    fn archive_read_support_format_all(a: *mut RawArchive) -> c_int;
+    fn archive_read_open_filename(
+        a: *mut RawArchive,
+        filename: *const c_char,
+        block_size: usize,
+    ) -> c_int;
+    fn archive_read_next_header(a: *mut RawArchive, entry: *mut *mut RawEntry) -> c_int;
+    fn archive_read_data_skip(a: *mut RawArchive) -> c_int;
+    fn archive_read_free(a: *mut RawArchive) -> c_int;
+    fn archive_error_string(a: *mut RawArchive) -> *const c_char;
+    fn archive_entry_pathname(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_symlink(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_hardlink(entry: *mut RawEntry) -> *const c_char;
+    fn archive_entry_xattr_reset(entry: *mut RawEntry) -> c_int;
+    fn archive_entry_xattr_next(
+        entry: *mut RawEntry,
+        name: *mut *const c_char,
+        value: *mut *const c_void,
+        size: *mut usize,
+    ) -> c_int;
+    fn archive_entry_acl_reset(entry: *mut RawEntry, want_type: c_int) -> c_int;
+    fn archive_entry_acl_next(
+        entry: *mut RawEntry,
+        want_type: c_int,
+        acl_type: *mut c_int,
+        permset: *mut c_int,
+        tag: *mut c_int,
+        qual: *mut c_int,
+        name: *mut *const c_char,
+    ) -> c_int;
+}
+
+#[derive(Debug, Clone)]
+pub enum Link {
+    Symlink(String),
+    Hardlink(String),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EntryMeta {
+    pub symlink: Option<String>,
+    pub hardlink: Option<String>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// acls encoded in the format of the system.posix_acl_* xattrs, empty if there are none
+    pub acl_access: Vec<u8>,
+    pub acl_default: Vec<u8>,
+}
+
+impl EntryMeta {
+    pub fn link(&self) -> Option<Link> {
+        match (&self.symlink, &self.hardlink) {
+            (Some(target), _) => Some(Link::Symlink(target.clone())),
+            (None, Some(target)) => Some(Link::Hardlink(target.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.symlink.is_none()
+            && self.hardlink.is_none()
+            && self.xattrs.is_empty()
+            && self.acl_access.is_empty()
+            && self.acl_default.is_empty()
+    }
+}
+
+/// Read the metadata of the package at path the first time it is needed
+pub fn load_metadata<'a>(
+    metadata: &'a mut Option<HashMap<String, EntryMeta>>,
+    path: &str,
+) -> Result<&'a HashMap<String, EntryMeta>> {
+    if metadata.is_none() {
+        *metadata = Some(sandbox::read_metadata(path)?);
+    }
+    Ok(metadata.as_ref().unwrap())
+}
+
+/// The link an entry of the package at path is, if any
+///
+/// compress-tools does not give link targets and hardlinks show up as empty files, so only
+/// symlinks and empty files need their metadata looked up.
+pub fn entry_link(
+    metadata: &mut Option<HashMap<String, EntryMeta>>,
+    path: &str,
+    file: &str,
+    stat: &stat,
+) -> Result<Option<Link>> {
+    let kind = SFlag::from_bits_truncate(stat.st_mode & SFlag::S_IFMT.bits());
+    if kind != SFlag::S_IFLNK && (kind != SFlag::S_IFREG || stat.st_size != 0) {
+        return Ok(None);
+    }
+    Ok(load_metadata(metadata, path)?
+        .get(file)
+        .and_then(EntryMeta::link))
+}
+
+struct Archive(*mut RawArchive);
+
+impl Archive {
+    fn error(&self) -> String {
+        unsafe { to_string(archive_error_string(self.0)) }.unwrap_or_default()
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        unsafe { archive_read_free(self.0) };
+    }
+}
+
+unsafe fn to_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn read_xattrs(entry: *mut RawEntry) -> Vec<(String, Vec<u8>)> {
+    let mut xattrs = Vec::new();
+
+    if archive_entry_xattr_reset(entry) == 0 {
+        return xattrs;
+    }
+
+    loop {
+        let mut name = ptr::null();
+        let mut value = ptr::null();
+        let mut size = 0;
+
+        if archive_entry_xattr_next(entry, &mut name, &mut value, &mut size) != ARCHIVE_OK {
+            break;
+        }
+
+        let Some(name) = to_string(name) else {
+            continue;
+        };
+        let value = match value.is_null() {
+            true => Vec::new(),
+            false => slice::from_raw_parts(value.cast::<u8>(), size).to_vec(),
+        };
+        xattrs.push((name, value));
+    }
+
+    xattrs
+}
+
+unsafe fn read_acl(entry: *mut RawEntry, want_type: c_int) -> Vec<u8> {
+    let mut entries = Vec::new();
+
+    if archive_entry_acl_reset(entry, want_type) == 0 {
+        return Vec::new();
+    }
+
+    loop {
+        let (mut acl_type, mut perm, mut tag, mut qual) = (0, 0, 0, 0);
+        let mut name = ptr::null();
+
+        let ret = archive_entry_acl_next(
+            entry,
+            want_type,
+            &mut acl_type,
+            &mut perm,
+            &mut tag,
+            &mut qual,
+            &mut name,
+        );
+        if ret != ARCHIVE_OK {
+            break;
+        }
+
+        // some archivers only store the user or group name
+        let name = to_string(name);
+        let user = || match qual {
+            -1 => User::from_name(name.as_deref()?)
+                .ok()?
+                .map(|u| u.uid.as_raw()),
+            _ => Some(qual as u32),
+        };
+        let group = || match qual {
+            -1 => Group::from_name(name.as_deref()?)
+                .ok()?
+                .map(|g| g.gid.as_raw()),
+            _ => Some(qual as u32),
+        };
+
+        // tags as used by linux's posix acl xattrs
+        let (tag, id) = match tag {
+            ACL_USER_OBJ => (0x01, u32::MAX),
+            ACL_USER => match user() {
+                Some(uid) => (0x02, uid),
+                None => continue,
+            },
+            ACL_GROUP_OBJ => (0x04, u32::MAX),
+            ACL_GROUP => match group() {
+                Some(gid) => (0x08, gid),
+                None => continue,
+            },
+            ACL_MASK => (0x10, u32::MAX),
+            ACL_OTHER => (0x20, u32::MAX),
+            _ => continue,
+        };
+        entries.push((tag as u16, (perm & 0o7) as u16, id));
+    }
+
+    if entries.is_empty() {
+        return Vec::new();
+    }
+
+    entries.sort_unstable_by_key(|&(tag, _, id)| (tag, id));
+
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    acl
+}
+
+pub fn read_metadata(path: &str) -> Result<HashMap<String, EntryMeta>> {
+    let cpath = CString::new(path).with_context(|| format!("invalid path {}", path))?;
+    let archive = Archive(unsafe { archive_read_new() });
+    let mut entries = HashMap::new();
+
+    if archive.0.is_null() {
+        bail!("failed to read {}", path);
+    }
+
+    unsafe {
+        archive_read_support_filter_all(archive.0);
+        archive_read_support_format_all(archive.0);
+
+        if archive_read_open_filename(archive.0, cpath.as_ptr(), 10240) != ARCHIVE_OK {
+            bail!("failed to read {}: {}", path, archive.error());
+        }
+    }
+
+    loop {
+        let mut entry = ptr::null_mut();
+        let ret = unsafe { archive_read_next_header(archive.0, &mut entry) };
+
+        if ret == ARCHIVE_EOF {
+            break;
+        }
+        if ret < ARCHIVE_WARN {
+            bail!("failed to read {}: {}", path, archive.error());
+        }
+
+        let meta = unsafe {
+            EntryMeta {
+                symlink: to_string(archive_entry_symlink(entry)),
+                hardlink: to_string(archive_entry_hardlink(entry)),
+                xattrs: read_xattrs(entry),
+                acl_access: read_acl(entry, ACL_TYPE_ACCESS),
+                acl_default: read_acl(entry, ACL_TYPE_DEFAULT),
+            }
+        };
+
+        if !meta.is_empty() {
+            if let Some(name) = unsafe { to_string(archive_entry_pathname(entry)) } {
+                entries.insert(name, meta);
+            }
+        }
+
+        unsafe { archive_read_data_skip(archive.0) };
+    }
+
+    Ok(entries)
+}
@@ -23,6 +23,45 @@ pub enum ColorWhen {
     Never,
 }
 
+#[derive(Copy, Clone, Default, Debug, ValueEnum)]
+pub enum Collision {
+    #[default]
+    Error,
+    Rename,
+    Overwrite,
+    PrefixWithPackage,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preserve {
+    Mode,
+    Owner,
+    Timestamps,
+    Xattrs,
+    Acl,
+}
+
+impl Preserve {
+    /// Whether the attribute has to be read from the archive separately to the file content
+    pub fn needs_metadata(&self) -> bool {
+        matches!(self, Preserve::Xattrs | Preserve::Acl)
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug, ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+    Jsonl,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum Content {
+    Utf8,
+    Base64,
+}
+
 #[derive(Parser, Debug)]
 #[command(
     help_template(TEMPLATE),
@@ -50,6 +89,15 @@ pub struct Args {
     #[arg(long, value_name = "when", value_enum, default_value_t = ColorWhen::Auto)]
     /// Specify when to enable coloring
     pub color: ColorWhen,
+    #[arg(long, value_name = "format", value_enum, default_value_t = Format::Text)]
+    /// Specify the output format
+    pub format: Format,
+    #[arg(long, value_name = "encoding", value_enum)]
+    /// Include file content in json output
+    pub content: Option<Content>,
+    #[arg(long, value_name = "template", value_parser = parse_template, conflicts_with = "format")]
+    /// Print listed, extracted or installed files using a template, e.g. '{repo}/{pkg} {path}'
+    pub format_string: Option<String>,
     #[arg(long, short = 'y', action = ArgAction::Count)]
     /// Download fresh package databases from the server
     pub refresh: u8,
@@ -65,18 +113,232 @@ pub struct Args {
     #[arg(long)]
     /// Print binary files
     pub binary: bool,
+    #[arg(long)]
+    /// Print the target of symlinks instead of the file they point to
+    pub no_follow: bool,
+    #[arg(long, overrides_with = "no_headers")]
+    /// Print a header before each file, the default when printing several files to a terminal
+    pub headers: bool,
+    #[arg(long, overrides_with = "headers")]
+    /// Never print a header before each file
+    pub no_headers: bool,
+    #[arg(short = 'H', long)]
+    /// Prefix each line printed with the package and path of the file
+    pub with_filename: bool,
+    #[arg(
+        long,
+        value_name = "regex",
+        conflicts_with_all = [
+            "format",
+            "format_string",
+            "extract",
+            "install",
+            "destdir",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+            "check",
+            "restore",
+        ]
+    )]
+    /// Print lines of matched files that match a regex, or with --list the files that do
+    pub grep: Option<String>,
+    #[arg(short = 'A', long, value_name = "n", requires = "grep")]
+    /// Print n lines after each line matched by --grep
+    pub after_context: Option<usize>,
+    #[arg(short = 'B', long, value_name = "n", requires = "grep")]
+    /// Print n lines before each line matched by --grep
+    pub before_context: Option<usize>,
+    #[arg(short = 'C', long, value_name = "n", requires = "grep")]
+    /// Print n lines before and after each line matched by --grep
+    pub context: Option<usize>,
     /// Filter results to executable files
     #[arg(long, short = 'X')]
     pub executable: bool,
     #[arg(short = 'e', long)]
     /// Extract matched files to the current directory
     pub extract: bool,
+    #[arg(long, value_name = "dir", value_hint = ValueHint::DirPath, requires = "extract")]
+    /// Extract files to the given directory instead of the current directory
+    pub output_dir: Option<String>,
+    #[arg(long, requires = "extract")]
+    /// Keep the directory structure of the package when extracting
+    pub preserve_paths: bool,
+    #[arg(
+        long,
+        value_name = "policy",
+        value_enum,
+        default_value_t = Collision::Error,
+        requires = "extract"
+    )]
+    /// What to do when extracting multiple files to the same path
+    pub collision: Collision,
+    #[arg(long, value_name = "attrs", value_enum, value_delimiter = ',')]
+    /// Preserve the given file attributes when extracting or installing
+    pub preserve: Vec<Preserve>,
+    #[arg(long)]
+    /// Keep setuid, setgid and sticky bits when extracting, installing or restoring
+    pub allow_setuid: bool,
+    #[arg(long, value_name = "size", value_parser = parse_size)]
+    /// Abort if a file in a package is larger than this (e.g. 512K, 100M, 2G)
+    pub max_file_size: Option<u64>,
+    #[arg(long, value_name = "size", value_parser = parse_size)]
+    /// Abort if the files in a package add up to more than this
+    pub max_total_size: Option<u64>,
+    #[arg(long, value_name = "n")]
+    /// Abort if a package has more than this many entries
+    pub max_entries: Option<u64>,
+    #[arg(long, value_name = "user")]
+    /// User to download and parse packages as when running as root
+    pub download_user: Option<String>,
     #[arg(long, short, conflicts_with = "extract")]
     /// Install matched files to the system
     pub install: bool,
     #[arg(short, long)]
     /// Print file names instead of file content
     pub list: bool,
+    #[arg(short, long, conflicts_with_all = ["extract", "install", "list"])]
+    /// Compare matched files against the installed files
+    pub diff: bool,
+    #[arg(
+        long,
+        value_name = "target",
+        conflicts_with_all = ["extract", "install", "list", "diff"]
+    )]
+    /// Compare matched files against the same files in another target
+    pub diff_with: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["extract", "install", "list", "diff_with"]
+    )]
+    /// Compare the contents of two packages
+    pub pkgdiff: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["extract", "install", "list", "diff_with", "pkgdiff"]
+    )]
+    /// Compare backup files of pending upgrades against the installed files
+    pub upgrade_preview: bool,
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+        ]
+    )]
+    /// Merge a file with its .pacnew using the previously installed version as the base
+    pub merge: bool,
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "filedb",
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+        ]
+    )]
+    /// Verify installed files against the contents of their package
+    pub check: bool,
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "filedb",
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+            "check",
+        ]
+    )]
+    /// Restore installed files that differ from the contents of their package
+    pub restore: bool,
+    #[arg(long, requires = "restore")]
+    /// Also restore modified backup files
+    pub include_backup: bool,
+    #[arg(long)]
+    /// Print what would be changed without writing any files
+    pub dry_run: bool,
+    #[arg(
+        long,
+        value_name = "dir",
+        value_hint = ValueHint::DirPath,
+        conflicts_with_all = [
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+            "check",
+            "restore",
+        ]
+    )]
+    /// Install matched files to a staging directory instead of the system
+    pub destdir: Option<String>,
+    #[arg(long, value_name = "file", value_hint = ValueHint::FilePath)]
+    /// Write a list of the installed files to a file
+    pub manifest: Option<String>,
+    #[arg(long, requires = "install")]
+    /// Do not ask for confirmation before installing files
+    pub noconfirm: bool,
+    #[arg(long, requires = "install")]
+    /// Install files owned by other packages or protected by NoExtract, NoUpgrade or backup
+    pub force: bool,
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+            "check",
+            "restore",
+        ]
+    )]
+    /// List previous install operations
+    pub history: bool,
+    #[arg(
+        long,
+        value_name = "id",
+        num_args = 0..=1,
+        conflicts_with_all = [
+            "extract",
+            "install",
+            "list",
+            "diff",
+            "diff_with",
+            "pkgdiff",
+            "upgrade_preview",
+            "merge",
+            "check",
+            "restore",
+            "history",
+        ]
+    )]
+    /// Roll back an install operation, defaults to the last one
+    pub undo: Option<Option<u64>>,
     #[arg(
         value_name = "targets",
         value_hint = ValueHint::AnyPath,
@@ -90,3 +352,74 @@ pub struct Args {
     )]
     pub files: Vec<String>,
 }
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size suffix '{}'", unit)),
+    };
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// The placeholders that can be used in --format-string
+pub const PLACEHOLDERS: &[&str] = &[
+    "pkg", "version", "repo", "path", "name", "dest", "type", "size", "bytes", "mode", "uid",
+    "gid", "mtime", "sha256", "link",
+];
+
+/// Replace each {placeholder} in a template, with {{ and }} for literal braces
+pub fn expand_template<F>(template: &str, mut field: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed '{{' in '{}'", template))?;
+                let name = &rest[..end];
+                let value = field(name).ok_or_else(|| {
+                    format!(
+                        "unknown placeholder '{{{}}}' (valid placeholders are {})",
+                        name,
+                        PLACEHOLDERS.join(", ")
+                    )
+                })?;
+                out.push_str(&value);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(format!("unmatched '}}' in '{}'", template)),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+fn parse_template(s: &str) -> Result<String, String> {
+    expand_template(s, |name| PLACEHOLDERS.contains(&name).then(String::new))?;
+    Ok(s.to_string())
+}
@@ -0,0 +1,172 @@
+use crate::dest::Dest;
+use anyhow::{Context, Result};
+use nix::fcntl::{openat, renameat, AtFlags, OFlag};
+use nix::libc::{c_char, c_int};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::{linkat, symlinkat, unlinkat, UnlinkatFlags};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Once;
+
+// path of the temporary file currently being written so it can be removed on signal
+static TEMP_PATH: AtomicPtr<c_char> = AtomicPtr::new(ptr::null_mut());
+static HANDLER: Once = Once::new();
+
+extern "C" fn cleanup_handler(sig: c_int) {
+    let path = TEMP_PATH.load(Ordering::SeqCst);
+    if !path.is_null() {
+        unsafe { nix::libc::unlink(path) };
+    }
+    // SA_RESETHAND restored the default action so this terminates once the handler returns
+    unsafe { nix::libc::raise(sig) };
+}
+
+fn install_handler() {
+    HANDLER.call_once(|| {
+        let action = SigAction::new(
+            SigHandler::Handler(cleanup_handler),
+            SaFlags::SA_RESETHAND,
+            SigSet::empty(),
+        );
+        for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+            let _ = unsafe { sigaction(sig, &action) };
+        }
+    });
+}
+
+fn set_temp_path(path: Option<&Path>) {
+    let new = path
+        .and_then(|p| CString::new(p.as_os_str().as_bytes()).ok())
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut());
+    let old = TEMP_PATH.swap(new, Ordering::SeqCst);
+    if !old.is_null() {
+        drop(unsafe { CString::from_raw(old) });
+    }
+}
+
+fn temp_name(name: &str) -> String {
+    format!(".{}.paccat-{}", name, std::process::id())
+}
+
+/// A file that is written to a temporary path and renamed over the destination on commit
+///
+/// Both names are resolved relative to the destination's directory so the rename can not be
+/// redirected by a symlink swapped in while the file is written.
+pub struct AtomicFile {
+    file: File,
+    dest: Dest,
+    temp: String,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn new(dest: &Path, mode: u32) -> Result<Self> {
+        Self::create(Dest::parent_of(dest)?, mode)
+    }
+
+    pub fn create(dest: Dest, mode: u32) -> Result<Self> {
+        install_handler();
+
+        let temp = temp_name(&dest.name);
+        let flags =
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = openat(
+            Some(dest.dir.as_raw_fd()),
+            temp.as_str(),
+            flags,
+            Mode::from_bits_truncate(mode & 0o7777),
+        )
+        .with_context(|| format!("failed to open {}", dest.sibling(&temp).display()))?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        set_temp_path(Some(&dest.sibling(&temp)));
+
+        Ok(Self {
+            file,
+            dest,
+            temp,
+            committed: false,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.file.sync_all().with_context(|| {
+            format!("failed to sync {}", self.dest.sibling(&self.temp).display())
+        })?;
+        let dir = self.dest.dir.as_raw_fd();
+        renameat(
+            Some(dir),
+            self.temp.as_str(),
+            Some(dir),
+            self.dest.name.as_str(),
+        )
+        .with_context(|| format!("failed to rename to {}", self.dest.path))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+/// Create a symlink at a temporary path and rename it over the destination
+pub fn symlink(dest: &Dest, target: &str) -> Result<()> {
+    place(dest, |dir, temp| symlinkat(target, Some(dir), temp))
+}
+
+/// Create a hardlink to src at a temporary path and rename it over the destination
+pub fn hardlink(dest: &Dest, src: &Dest) -> Result<()> {
+    place(dest, |dir, temp| {
+        linkat(
+            Some(src.dir.as_raw_fd()),
+            src.name.as_str(),
+            Some(dir),
+            temp,
+            AtFlags::empty(),
+        )
+    })
+}
+
+fn place(dest: &Dest, create: impl FnOnce(RawFd, &str) -> nix::Result<()>) -> Result<()> {
+    install_handler();
+
+    let dir = dest.dir.as_raw_fd();
+    let temp = temp_name(&dest.name);
+    create(dir, &temp).with_context(|| format!("failed to link {}", dest.path))?;
+    set_temp_path(Some(&dest.sibling(&temp)));
+
+    let res = renameat(Some(dir), temp.as_str(), Some(dir), dest.name.as_str());
+    set_temp_path(None);
+    if res.is_err() {
+        let _ = unlinkat(Some(dir), temp.as_str(), UnlinkatFlags::NoRemoveDir);
+    }
+    res.with_context(|| format!("failed to rename to {}", dest.path))
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        set_temp_path(None);
+        if !self.committed {
+            let dir = self.dest.dir.as_raw_fd();
+            let _ = unlinkat(Some(dir), self.temp.as_str(), UnlinkatFlags::NoRemoveDir);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::archive::{entry_link, EntryMeta, Link};
+use crate::args::Args;
+use crate::compare::Compare;
+use crate::limits::Limits;
+use crate::pacman::{get_dbpkg, get_installed_pkgfile, match_patterns};
+use crate::{entry_type, open_archive, Match};
+use alpm::{Alpm, Package};
+use anyhow::Result;
+use compress_tools::ArchiveContents;
+use nix::sys::stat::{Mode, SFlag};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+struct Pending {
+    compare: Compare,
+    entry: String,
+    backup: bool,
+}
+
+pub fn check(alpm: &Alpm, args: &Args) -> Result<bool> {
+    let mut pkgs = Vec::new();
+    let mut altered = false;
+
+    if args.targets.is_empty() {
+        pkgs.extend(alpm.localdb().pkgs());
+    } else {
+        for targ in &args.targets {
+            pkgs.push(get_dbpkg(alpm, targ, true)?);
+        }
+    }
+
+    let mut matcher = match args.files.is_empty() {
+        true => None,
+        false => {
+            let files = args
+                .files
+                .iter()
+                .map(|f| f.trim_start_matches('/').to_string())
+                .collect();
+            Some(Match::new(args.regex, files)?)
+        }
+    };
+
+    for pkg in pkgs {
+        match check_pkg(alpm, args, pkg, matcher.as_mut()) {
+            Ok(a) => altered |= a,
+            Err(e) if args.targets.is_empty() => {
+                writeln!(io::stderr(), "warning: skipping {}: {:#}", pkg.name(), e)?;
+                altered = true;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(altered)
+}
+
+fn report(pkg: &Package, path: &str, msg: std::fmt::Arguments) -> Result<()> {
+    writeln!(io::stdout(), "{}: {} {}", pkg.name(), path, msg)?;
+    Ok(())
+}
+
+fn check_pkg(
+    alpm: &Alpm,
+    args: &Args,
+    pkg: &Package,
+    mut matcher: Option<&mut Match>,
+) -> Result<bool> {
+    let pkgfile = get_installed_pkgfile(alpm, pkg)?;
+    let backup = pkg.backup();
+    let mut links: Option<HashMap<String, EntryMeta>> = None;
+    let mut current: Option<Pending> = None;
+    let mut total = 0;
+    let mut altered = 0;
+    let mut failed = false;
+    let mut limits = Limits::new(args);
+
+    for content in open_archive(&pkgfile)? {
+        limits.check(&content)?;
+        match content {
+            ArchiveContents::StartOfEntry(file, stat) => {
+                if file.starts_with('.') && !file.contains('/') {
+                    continue;
+                }
+                if let Some(matcher) = &mut matcher {
+                    if !matcher.is_match(&file, false) {
+                        continue;
+                    }
+                }
+
+                let path = format!("{}{}", alpm.root(), file);
+                let kind = SFlag::from_bits_truncate(stat.st_mode & SFlag::S_IFMT.bits());
+                let mode = Mode::from_bits_truncate(stat.st_mode);
+                let is_backup = backup.iter().any(|b| b.name() == file);
+                let mut changed = false;
+                total += 1;
+
+                let meta = match fs::symlink_metadata(&path) {
+                    Ok(meta) => meta,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        if !match_patterns(alpm.noextracts(), &file) {
+                            report(pkg, &path, format_args!("missing"))?;
+                            altered += 1;
+                            failed = true;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        report(pkg, &path, format_args!("could not be read: {}", e))?;
+                        continue;
+                    }
+                };
+
+                let disk_kind = meta.mode() & SFlag::S_IFMT.bits();
+                if disk_kind != kind.bits() {
+                    report(
+                        pkg,
+                        &path,
+                        format_args!(
+                            "type mismatch ({} -> {})",
+                            entry_type(stat.st_mode),
+                            entry_type(meta.mode())
+                        ),
+                    )?;
+                    altered += 1;
+                    failed = true;
+                    continue;
+                }
+
+                if kind != SFlag::S_IFLNK {
+                    let disk_mode = meta.permissions().mode() & 0o7777;
+                    if disk_mode != mode.bits() {
+                        report(
+                            pkg,
+                            &path,
+                            format_args!(
+                                "mode mismatch ({:04o} -> {:04o})",
+                                mode.bits(),
+                                disk_mode
+                            ),
+                        )?;
+                        changed = true;
+                    }
+                    if meta.uid() != stat.st_uid || meta.gid() != stat.st_gid {
+                        report(
+                            pkg,
+                            &path,
+                            format_args!(
+                                "owner mismatch ({}:{} -> {}:{})",
+                                stat.st_uid,
+                                stat.st_gid,
+                                meta.uid(),
+                                meta.gid()
+                            ),
+                        )?;
+                        changed = true;
+                    }
+                }
+
+                let link = entry_link(&mut links, &pkgfile, &file, &stat)?;
+                let hardlink = match &link {
+                    Some(Link::Hardlink(target)) => Some(target),
+                    _ => None,
+                };
+
+                if kind == SFlag::S_IFLNK {
+                    let target = fs::read_link(&path)?;
+                    let expected = match &link {
+                        Some(Link::Symlink(target)) => Some(target.as_str()),
+                        _ => None,
+                    };
+                    if expected != target.to_str() {
+                        report(
+                            pkg,
+                            &path,
+                            format_args!(
+                                "symlink mismatch ({} -> {})",
+                                expected.unwrap_or(""),
+                                target.display()
+                            ),
+                        )?;
+                        changed = true;
+                    }
+                }
+
+                if let Some(target) = hardlink {
+                    let target = format!("{}{}", alpm.root(), target);
+                    let same = fs::symlink_metadata(&target)
+                        .map(|t| t.ino() == meta.ino() && t.dev() == meta.dev())
+                        .unwrap_or(false);
+                    if !same {
+                        report(pkg, &path, format_args!("hardlink mismatch ({})", target))?;
+                        changed = true;
+                    }
+                } else if kind == SFlag::S_IFREG {
+                    if meta.len() != stat.st_size as u64 {
+                        report_modified(pkg, &path, is_backup)?;
+                        changed |= !is_backup;
+                    } else {
+                        match File::open(&path) {
+                            Ok(file) => {
+                                current = Some(Pending {
+                                    compare: Compare::new(Some(file), meta.len())?,
+                                    entry: path,
+                                    backup: is_backup,
+                                });
+                            }
+                            Err(e) => report(pkg, &path, format_args!("could not be read: {}", e))?,
+                        }
+                    }
+                }
+
+                if changed {
+                    altered += 1;
+                    failed = true;
+                }
+            }
+            ArchiveContents::DataChunk(data) => {
+                if let Some(pending) = &mut current {
+                    pending.compare.update(&data);
+                }
+            }
+            ArchiveContents::EndOfEntry => {
+                if let Some(mut pending) = current.take() {
+                    if !pending.compare.finish() {
+                        report_modified(pkg, &pending.entry, pending.backup)?;
+                        if !pending.backup {
+                            altered += 1;
+                            failed = true;
+                        }
+                    }
+                }
+            }
+            ArchiveContents::Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+
+    writeln!(
+        io::stdout(),
+        "{}: {} total files, {} altered files",
+        pkg.name(),
+        total,
+        altered
+    )?;
+
+    Ok(failed)
+}
+
+fn report_modified(pkg: &Package, path: &str, backup: bool) -> Result<()> {
+    match backup {
+        true => report(pkg, path, format_args!("modified (backup file)")),
+        false => report(pkg, path, format_args!("modified")),
+    }
+}
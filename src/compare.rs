@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Compares an archive entry against a file on disk as its data is read
+pub struct Compare {
+    file: Option<File>,
+    buf: Vec<u8>,
+    matched: u64,
+    same: bool,
+}
+
+impl Compare {
+    /// Start comparing against `file`, which differs if it is missing or not `size` bytes long
+    pub fn new(file: Option<File>, size: u64) -> io::Result<Self> {
+        let same = match &file {
+            Some(file) => file.metadata()?.len() == size,
+            None => false,
+        };
+
+        Ok(Self {
+            file,
+            buf: Vec::new(),
+            matched: 0,
+            same,
+        })
+    }
+
+    pub fn same(&self) -> bool {
+        self.same
+    }
+
+    /// Compare the next chunk of the entry, returns whether everything so far is the same
+    pub fn update(&mut self, data: &[u8]) -> bool {
+        if let (true, Some(file)) = (self.same, &mut self.file) {
+            self.buf.resize(data.len(), 0);
+            self.same = file.read_exact(&mut self.buf).is_ok() && self.buf == data;
+            if self.same {
+                self.matched += data.len() as u64;
+            }
+        }
+        self.same
+    }
+
+    /// Check that the file has no more data than the entry, returns whether they are the same
+    pub fn finish(&mut self) -> bool {
+        if let (true, Some(file)) = (self.same, &mut self.file) {
+            self.same = file.read(&mut [0]).map(|n| n == 0).unwrap_or(false);
+        }
+        self.same
+    }
+
+    /// The start of the entry that matched the file before the first difference
+    ///
+    /// This is read back from the file so the entry does not need to be kept while it is the same.
+    pub fn matched(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(0))?;
+            file.take(self.matched).read_to_end(&mut data)?;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn compare(name: &str, installed: &[u8], chunks: &[&[u8]]) -> (bool, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("paccat-{}-{}", name, std::process::id()));
+        fs::write(&path, installed).unwrap();
+        let size = chunks.iter().map(|c| c.len() as u64).sum();
+        let mut compare = Compare::new(Some(File::open(&path).unwrap()), size).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut matched = None;
+        for chunk in chunks {
+            if !compare.update(chunk) && matched.is_none() {
+                matched = Some(compare.matched().unwrap());
+            }
+        }
+        (compare.finish(), matched.unwrap_or_default())
+    }
+
+    #[test]
+    fn same_across_chunks() {
+        assert_eq!(
+            compare("same", b"abcdef", &[b"abc", b"def"]),
+            (true, vec![])
+        );
+    }
+
+    #[test]
+    fn difference_keeps_matched_start() {
+        let (same, matched) = compare("diff", b"abcdefghi", &[b"abc", b"def", b"xyz"]);
+        assert!(!same);
+        assert_eq!(matched, b"abcdef");
+    }
+
+    #[test]
+    fn size_mismatch_differs() {
+        assert_eq!(compare("size", b"abc", &[b"abcd"]), (false, vec![]));
+    }
+
+    #[test]
+    fn missing_file_differs() {
+        let mut compare = Compare::new(None, 0).unwrap();
+        assert!(!compare.finish());
+    }
+}
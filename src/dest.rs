@@ -0,0 +1,327 @@
+use anyhow::{bail, ensure, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{openat, readlinkat, AtFlags, OFlag};
+use nix::sys::stat::{fstatat, mkdirat, FileStat, Mode, SFlag};
+use nix::unistd::{fchownat, unlinkat, Gid, Uid, UnlinkatFlags};
+use std::fs::{DirBuilder, File};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Refuse archive entries that are absolute or contain `..` and so could escape the destination
+pub fn check_name(file: &str) -> Result<()> {
+    ensure!(
+        !file.is_empty(),
+        "refusing to write an empty path from archive"
+    );
+    ensure!(
+        !file.starts_with('/'),
+        "refusing to write {}: absolute path in archive",
+        file
+    );
+    ensure!(
+        !file.split('/').any(|part| part == ".."),
+        "refusing to write {}: path escapes the destination",
+        file
+    );
+    Ok(())
+}
+
+pub fn is_type(stat: &FileStat, kind: SFlag) -> bool {
+    stat.st_mode & SFlag::S_IFMT.bits() == kind.bits()
+}
+
+fn open_dir(dir: Option<RawFd>, name: &str, follow: bool) -> nix::Result<OwnedFd> {
+    let mut flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    if !follow {
+        flags |= OFlag::O_NOFOLLOW;
+    }
+    let fd = openat(dir, name, flags, Mode::empty())?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// An entry in a directory that was opened without following symlinks
+pub struct Dest {
+    pub dir: OwnedFd,
+    pub name: String,
+    pub path: String,
+}
+
+impl Dest {
+    /// Open the directory that will contain path, which must be beneath root
+    ///
+    /// root itself is trusted and may be a symlink, but no directory below it may be. Missing
+    /// directories are created when create is set.
+    pub fn open(root: &str, path: &str, create: bool) -> Result<Self> {
+        let rel = path
+            .strip_prefix(root)
+            .with_context(|| format!("{} is not beneath {}", path, root))?;
+        let mut parts = rel
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<_>>();
+        let name = parts
+            .pop()
+            .with_context(|| format!("invalid path {}", path))?;
+        ensure!(
+            name != ".." && !parts.contains(&".."),
+            "refusing to write {}: path escapes the destination",
+            path
+        );
+
+        let root = match root {
+            "" => ".",
+            root => root,
+        };
+        if create {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(root)
+                .with_context(|| format!("failed to mkdir {}", root))?;
+        }
+        let mut dir =
+            open_dir(None, root, true).with_context(|| format!("failed to open {}", root))?;
+
+        for part in parts {
+            let fd = dir.as_raw_fd();
+            dir = match open_dir(Some(fd), part, false) {
+                Ok(dir) => dir,
+                Err(Errno::ENOENT) if create => {
+                    match mkdirat(Some(fd), part, Mode::from_bits_truncate(0o755)) {
+                        Ok(()) | Err(Errno::EEXIST) => (),
+                        Err(e) => {
+                            return Err(e).with_context(|| format!("failed to mkdir {}", path))
+                        }
+                    }
+                    open_dir(Some(fd), part, false)
+                        .with_context(|| format!("failed to open parent of {}", path))?
+                }
+                Err(Errno::ELOOP | Errno::ENOTDIR) => {
+                    let stat = fstatat(Some(fd), part, AtFlags::AT_SYMLINK_NOFOLLOW);
+                    match stat {
+                        Ok(stat) if is_type(&stat, SFlag::S_IFLNK) => {
+                            bail!("refusing to write {} through symlink {}", path, part)
+                        }
+                        _ => bail!("refusing to write {}: {} is not a directory", path, part),
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to open parent of {}", path))
+                }
+            };
+        }
+
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Open the parent of a path that is trusted, following symlinks
+    pub fn parent_of(path: &Path) -> Result<Self> {
+        let parent = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .with_context(|| format!("invalid path {}", path.display()))?;
+        let parent_str = parent.to_string_lossy();
+        let dir = open_dir(None, &parent_str, true)
+            .with_context(|| format!("failed to open {}", parent.display()))?;
+
+        Ok(Self {
+            dir,
+            name: name.to_string_lossy().into_owned(),
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// The path of another entry in the same directory
+    pub fn sibling(&self, name: &str) -> PathBuf {
+        Path::new(&self.path).with_file_name(name)
+    }
+
+    /// Stat the entry without following it if it is a symlink
+    pub fn stat(&self) -> Result<Option<FileStat>> {
+        match fstatat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to stat {}", self.path)),
+        }
+    }
+
+    /// Open the entry for reading without following it if it is a symlink
+    pub fn open_file(&self) -> Result<Option<File>> {
+        let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        match openat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_str(),
+            flags,
+            Mode::empty(),
+        ) {
+            Ok(fd) => Ok(Some(unsafe { File::from_raw_fd(fd) })),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to open {}", self.path)),
+        }
+    }
+
+    /// The target of the entry, which must be a symlink
+    pub fn read_link(&self) -> Result<String> {
+        let target = readlinkat(Some(self.dir.as_raw_fd()), self.name.as_str())
+            .with_context(|| format!("failed to read link {}", self.path))?;
+        Ok(target.to_string_lossy().into_owned())
+    }
+
+    /// Remove the entry, returning false if it is a directory that is not empty
+    ///
+    /// It is not an error if the entry is already gone.
+    pub fn remove(&self, dir: bool) -> Result<bool> {
+        let flag = match dir {
+            true => UnlinkatFlags::RemoveDir,
+            false => UnlinkatFlags::NoRemoveDir,
+        };
+        match unlinkat(Some(self.dir.as_raw_fd()), self.name.as_str(), flag) {
+            Ok(()) | Err(Errno::ENOENT) => Ok(true),
+            Err(Errno::ENOTEMPTY | Errno::EEXIST) if dir => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {}", self.path)),
+        }
+    }
+
+    /// Open the entry as a directory without following it if it is a symlink
+    pub fn open_dir(&self) -> Result<File> {
+        let dir = open_dir(Some(self.dir.as_raw_fd()), &self.name, false)
+            .with_context(|| format!("failed to open {}", self.path))?;
+        Ok(File::from(dir))
+    }
+
+    pub fn mkdir(&self, mode: u32) -> Result<()> {
+        mkdirat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_str(),
+            Mode::from_bits_truncate(mode & 0o7777),
+        )
+        .with_context(|| format!("failed to mkdir {}", self.path))
+    }
+
+    /// Change the owner of the entry itself, not what it points to
+    pub fn chown(&self, uid: u32, gid: u32) -> Result<()> {
+        fchownat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_str(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        )
+        .with_context(|| format!("failed to chown {}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// A directory that is removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("paccat-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// The path with a trailing / as alpm gives the root
+        fn root(&self) -> String {
+            format!("{}/", self.0.display())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn check_name_refuses_escapes() {
+        assert!(check_name("..").is_err());
+        assert!(check_name("usr/../../etc/passwd").is_err());
+        assert!(check_name("/etc/passwd").is_err());
+        assert!(check_name("").is_err());
+        assert!(check_name("usr/bin/foo").is_ok());
+        assert!(check_name("usr//bin/foo").is_ok());
+        assert!(check_name("usr/share/foo..bar").is_ok());
+    }
+
+    #[test]
+    fn open_refuses_dotdot() {
+        let tmp = TempDir::new("dest-dotdot");
+        let root = tmp.root();
+        assert!(Dest::open(&root, &format!("{}a/../b", root), true).is_err());
+        assert!(Dest::open(&root, &format!("{}..", root), true).is_err());
+        assert!(!tmp.0.join("a").exists());
+    }
+
+    #[test]
+    fn open_refuses_path_outside_root() {
+        let tmp = TempDir::new("dest-absolute");
+        assert!(Dest::open(&tmp.root(), "/etc/passwd", false).is_err());
+    }
+
+    #[test]
+    fn open_refuses_empty_name() {
+        let tmp = TempDir::new("dest-empty");
+        let root = tmp.root();
+        assert!(Dest::open(&root, &root, true).is_err());
+        assert!(Dest::open(&root, &format!("{}.", root), true).is_err());
+    }
+
+    #[test]
+    fn open_skips_empty_parts() {
+        let tmp = TempDir::new("dest-slashes");
+        let root = tmp.root();
+        let dest = Dest::open(&root, &format!("{}a//b", root), true).unwrap();
+        assert_eq!(dest.name, "b");
+        assert!(tmp.0.join("a").is_dir());
+    }
+
+    #[test]
+    fn open_refuses_symlinked_parent() {
+        let tmp = TempDir::new("dest-symlink");
+        let root = tmp.root();
+        fs::create_dir(tmp.0.join("real")).unwrap();
+        symlink("real", tmp.0.join("link")).unwrap();
+
+        let err = Dest::open(&root, &format!("{}link/file", root), true)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("through symlink link"), "{}", err);
+        let err = Dest::open(&root, &format!("{}link/new/file", root), true)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("through symlink link"), "{}", err);
+        assert!(!tmp.0.join("real/new").exists());
+    }
+
+    #[test]
+    fn open_allows_symlinked_root() {
+        let tmp = TempDir::new("dest-root");
+        fs::create_dir(tmp.0.join("real")).unwrap();
+        symlink("real", tmp.0.join("root")).unwrap();
+
+        let root = format!("{}/root/", tmp.0.display());
+        let dest = Dest::open(&root, &format!("{}a/b", root), true).unwrap();
+        assert_eq!(dest.name, "b");
+        assert!(tmp.0.join("real/a").is_dir());
+    }
+}
@@ -0,0 +1,492 @@
+use crate::archive::{entry_link, EntryMeta, Link};
+use crate::args::{Args, Format};
+use crate::limits::Limits;
+use crate::{entry_type, is_binary, open_archive, Match};
+use anyhow::{Context, Result};
+use compress_tools::ArchiveContents;
+use nix::libc::stat;
+use nix::sys::stat::{Mode, SFlag};
+use nix::unistd::{Gid, Group, Uid, User};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+const METADATA: &[&str] = &[".PKGINFO", ".BUILDINFO", ".MTREE"];
+
+#[derive(Serialize)]
+pub struct PkgEntry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: i64,
+    mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip)]
+    data: Option<Vec<u8>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize)]
+struct Change<'a> {
+    path: &'a str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old: Option<&'a PkgEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new: Option<&'a PkgEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PkgDiff<'a> {
+    old: &'a str,
+    new: &'a str,
+    changes: Vec<Change<'a>>,
+}
+
+pub fn read_entries(
+    pkg: &str,
+    matcher: &mut Match,
+    args: &Args,
+    entries: &mut BTreeMap<String, PkgEntry>,
+) -> Result<()> {
+    let mut current: Option<(String, PkgEntry, Sha256)> = None;
+    let mut metadata: Option<HashMap<String, EntryMeta>> = None;
+
+    let mut limits = Limits::new(args);
+
+    for content in open_archive(pkg)? {
+        limits.check(&content)?;
+        match content {
+            ArchiveContents::StartOfEntry(file, stat) => {
+                let mode = Mode::from_bits_truncate(stat.st_mode);
+
+                if METADATA.contains(&file.as_str()) {
+                    continue;
+                }
+
+                if args.executable && !mode.contains(Mode::S_IXUSR) {
+                    continue;
+                }
+
+                if matcher.is_match(&file, !args.all) {
+                    let mut kind = entry_type(stat.st_mode);
+
+                    let link = entry_link(&mut metadata, pkg, &file, &stat)?;
+                    let link = link.map(|link| match link {
+                        Link::Symlink(dest) => dest,
+                        Link::Hardlink(dest) => {
+                            kind = "hardlink";
+                            dest
+                        }
+                    });
+
+                    let entry = PkgEntry {
+                        kind,
+                        size: stat.st_size,
+                        mode: format!("{:04o}", mode.bits()),
+                        link,
+                        sha256: None,
+                        data: args.diff.then(Vec::new),
+                    };
+                    current = Some((file, entry, Sha256::new()));
+                }
+            }
+            ArchiveContents::DataChunk(data) => {
+                if let Some((_, entry, hasher)) = &mut current {
+                    hasher.update(&data);
+                    if let Some(buf) = &mut entry.data {
+                        buf.extend_from_slice(&data);
+                    }
+                }
+            }
+            ArchiveContents::EndOfEntry => {
+                if let Some((file, mut entry, hasher)) = current.take() {
+                    if entry.kind == "file" {
+                        entry.sha256 = Some(format!("{:x}", hasher.finalize()));
+                    }
+                    entries.insert(file, entry);
+                }
+            }
+            ArchiveContents::Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn print_pkgdiff(
+    old_name: &str,
+    new_name: &str,
+    old: &BTreeMap<String, PkgEntry>,
+    new: &BTreeMap<String, PkgEntry>,
+    args: &Args,
+    color: bool,
+) -> Result<bool> {
+    let mut changes = Vec::new();
+    let paths = old
+        .keys()
+        .chain(new.keys().filter(|f| !old.contains_key(*f)));
+
+    for path in paths {
+        let (old, new) = (old.get(path), new.get(path));
+        let status = match (old, new) {
+            (Some(_), None) => Status::Removed,
+            (None, Some(_)) => Status::Added,
+            (Some(o), Some(n))
+                if o.kind == n.kind
+                    && o.mode == n.mode
+                    && o.link == n.link
+                    && o.sha256 == n.sha256 =>
+            {
+                continue
+            }
+            _ => Status::Modified,
+        };
+        changes.push(Change {
+            path,
+            status,
+            old,
+            new,
+            diff: None,
+        });
+    }
+    changes.sort_by(|a, b| a.path.cmp(b.path));
+
+    let differ = !changes.is_empty();
+
+    if !matches!(args.format, Format::Text) {
+        for change in &mut changes {
+            if let (Some(o), Some(n)) = (change.old, change.new) {
+                if let (Some(old), Some(new)) = (&o.data, &n.data) {
+                    let mut diff = Vec::new();
+                    let old_path = format!("a/{}", change.path);
+                    let new_path = format!("b/{}", change.path);
+                    write_diff(&mut diff, &old_path, &new_path, old, new, false)?;
+                    change.diff = Some(String::from_utf8_lossy(&diff).into_owned());
+                }
+            }
+        }
+
+        let report = PkgDiff {
+            old: old_name,
+            new: new_name,
+            changes,
+        };
+        let mut stdout = io::stdout().lock();
+        match args.format {
+            Format::Jsonl => serde_json::to_writer(&mut stdout, &report)?,
+            _ => serde_json::to_writer_pretty(&mut stdout, &report)?,
+        }
+        writeln!(stdout)?;
+        return Ok(differ);
+    }
+
+    let (mut added, mut removed, mut modified) = (0, 0, 0);
+    let mut stdout = io::stdout().lock();
+    let paint = |c: &'static str| if color { (c, RESET) } else { ("", "") };
+
+    writeln!(stdout, "--- {}", old_name)?;
+    writeln!(stdout, "+++ {}", new_name)?;
+
+    for change in &changes {
+        match (change.old, change.new) {
+            (None, Some(n)) => {
+                added += 1;
+                let (start, end) = paint(GREEN);
+                writeln!(
+                    stdout,
+                    "{}A {}{} ({}, {})",
+                    start, change.path, end, n.kind, n.size
+                )?;
+            }
+            (Some(o), None) => {
+                removed += 1;
+                let (start, end) = paint(RED);
+                writeln!(
+                    stdout,
+                    "{}D {}{} ({}, {})",
+                    start, change.path, end, o.kind, o.size
+                )?;
+            }
+            (Some(o), Some(n)) => {
+                modified += 1;
+                let (start, end) = paint(YELLOW);
+                write!(stdout, "{}M {}{}", start, change.path, end)?;
+                if o.kind != n.kind {
+                    write!(stdout, " type {} -> {}", o.kind, n.kind)?;
+                }
+                if o.size != n.size {
+                    write!(stdout, " size {} -> {}", o.size, n.size)?;
+                }
+                if o.mode != n.mode {
+                    write!(stdout, " mode {} -> {}", o.mode, n.mode)?;
+                }
+                if o.link != n.link {
+                    let old = o.link.as_deref().unwrap_or("-");
+                    let new = n.link.as_deref().unwrap_or("-");
+                    write!(stdout, " link {} -> {}", old, new)?;
+                }
+                writeln!(stdout)?;
+
+                if let (Some(old), Some(new)) = (&o.data, &n.data) {
+                    let old_path = format!("a/{}", change.path);
+                    let new_path = format!("b/{}", change.path);
+                    write_diff(&mut stdout, &old_path, &new_path, old, new, color)?;
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    writeln!(
+        stdout,
+        "{} added, {} removed, {} modified",
+        added, removed, modified
+    )?;
+
+    Ok(differ)
+}
+
+pub fn read_files<I>(
+    archive: I,
+    matcher: &mut Match,
+    args: &Args,
+    files: &mut BTreeMap<String, Vec<u8>>,
+) -> Result<()>
+where
+    I: Iterator<Item = ArchiveContents>,
+{
+    let mut current = None;
+
+    let mut limits = Limits::new(args);
+
+    for content in archive {
+        limits.check(&content)?;
+        match content {
+            ArchiveContents::StartOfEntry(file, stat) => {
+                let mode = Mode::from_bits_truncate(stat.st_mode);
+                let kind = SFlag::from_bits_truncate(stat.st_mode);
+
+                if kind != SFlag::S_IFREG {
+                    continue;
+                }
+
+                if args.executable && !mode.contains(Mode::S_IXUSR) {
+                    continue;
+                }
+
+                if matcher.is_match(&file, !args.all) {
+                    current = Some((file, Vec::new()));
+                }
+            }
+            ArchiveContents::DataChunk(data) => {
+                if let Some((_, buf)) = &mut current {
+                    buf.extend_from_slice(&data);
+                }
+            }
+            ArchiveContents::EndOfEntry => {
+                if let Some((file, buf)) = current.take() {
+                    files.insert(file, buf);
+                }
+            }
+            ArchiveContents::Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn diff_files(
+    old: &BTreeMap<String, Vec<u8>>,
+    new: &BTreeMap<String, Vec<u8>>,
+    color: bool,
+) -> Result<bool> {
+    let mut differ = false;
+
+    for (file, data) in old {
+        match new.get(file) {
+            Some(new_data) => {
+                let old_name = format!("a/{}", file);
+                let new_name = format!("b/{}", file);
+                differ |= print_diff(&old_name, &new_name, data, new_data, color)?;
+            }
+            None => {
+                writeln!(io::stdout(), "removed: {}", file)?;
+                differ = true;
+            }
+        }
+    }
+
+    for file in new.keys().filter(|f| !old.contains_key(*f)) {
+        writeln!(io::stdout(), "added: {}", file)?;
+        differ = true;
+    }
+
+    Ok(differ)
+}
+
+fn owner(uid: u32, gid: u32) -> String {
+    let user = User::from_uid(Uid::from_raw(uid)).ok().flatten();
+    let group = Group::from_gid(Gid::from_raw(gid)).ok().flatten();
+    let user = user.map(|u| u.name).unwrap_or_else(|| uid.to_string());
+    let group = group.map(|g| g.name).unwrap_or_else(|| gid.to_string());
+    format!("{}:{}", user, group)
+}
+
+fn diff_stat(old: &[u8], new: &[u8]) -> String {
+    if old == new {
+        return "unchanged".to_string();
+    }
+    if is_binary(old) || is_binary(new) {
+        return "binary file differs".to_string();
+    }
+
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let diff = TextDiff::from_lines(old.as_ref(), new.as_ref());
+    let (mut insert, mut delete) = (0, 0);
+
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => insert += 1,
+            ChangeTag::Delete => delete += 1,
+            ChangeTag::Equal => (),
+        }
+    }
+
+    format!("+{} -{}", insert, delete)
+}
+
+pub fn print_preview(path: &str, stat: &stat, data: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    match fs::read(path) {
+        Ok(installed) => {
+            let meta = fs::metadata(path)?;
+            writeln!(
+                stdout,
+                "overwrite {} (mode {:04o}, owner {}, {})",
+                path,
+                meta.mode() & 0o7777,
+                owner(meta.uid(), meta.gid()),
+                diff_stat(&installed, data)
+            )?;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            writeln!(
+                stdout,
+                "create {} (mode {:04o}, owner {}, {} bytes)",
+                path,
+                stat.st_mode & 0o7777,
+                owner(stat.st_uid, stat.st_gid),
+                data.len()
+            )?;
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path)),
+    }
+
+    Ok(())
+}
+
+pub fn diff_installed(root: &str, file: &str, data: &[u8], color: bool) -> Result<bool> {
+    let path = format!("{}{}", root, file);
+
+    let installed = match fs::read(&path) {
+        Ok(installed) => installed,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            writeln!(io::stdout(), "Only in package: {}", file)?;
+            return Ok(true);
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path)),
+    };
+
+    print_diff(file, &path, data, &installed, color)
+}
+
+pub fn print_diff(
+    old_name: &str,
+    new_name: &str,
+    old: &[u8],
+    new: &[u8],
+    color: bool,
+) -> Result<bool> {
+    let mut stdout = io::stdout().lock();
+    write_diff(&mut stdout, old_name, new_name, old, new, color)
+}
+
+fn write_diff<W: Write>(
+    mut stdout: W,
+    old_name: &str,
+    new_name: &str,
+    old: &[u8],
+    new: &[u8],
+    color: bool,
+) -> Result<bool> {
+    if old == new {
+        return Ok(false);
+    }
+
+    if is_binary(old) || is_binary(new) {
+        writeln!(stdout, "Binary files {} and {} differ", old_name, new_name)?;
+        return Ok(true);
+    }
+
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let diff = TextDiff::from_lines(old.as_ref(), new.as_ref());
+
+    if !color {
+        diff.unified_diff()
+            .header(old_name, new_name)
+            .to_writer(&mut stdout)?;
+        return Ok(true);
+    }
+
+    writeln!(stdout, "{}--- {}{}", BOLD, old_name, RESET)?;
+    writeln!(stdout, "{}+++ {}{}", BOLD, new_name, RESET)?;
+
+    for hunk in diff.unified_diff().iter_hunks() {
+        writeln!(stdout, "{}{}{}", CYAN, hunk.header(), RESET)?;
+        for change in hunk.iter_changes() {
+            let (sign, start) = match change.tag() {
+                ChangeTag::Delete => ("-", RED),
+                ChangeTag::Insert => ("+", GREEN),
+                ChangeTag::Equal => (" ", ""),
+            };
+            let line = change.value().trim_end_matches('\n');
+            let end = if start.is_empty() { "" } else { RESET };
+            writeln!(stdout, "{}{}{}{}", start, sign, line, end)?;
+            if change.missing_newline() {
+                writeln!(stdout, "\\ No newline at end of file")?;
+            }
+        }
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,271 @@
+use crate::archive::Link;
+use crate::args::{self, Args, Content, Format};
+use crate::{entry_type, Target};
+use anyhow::{anyhow, Result};
+use nix::libc::stat;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// whether an entry has been written yet, so --format json can separate array elements
+static EMITTED: AtomicBool = AtomicBool::new(false);
+
+/// A matched entry of a package as printed by --format json and jsonl
+#[derive(Serialize)]
+pub struct Record {
+    package: String,
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<String>,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dest: Option<String>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: i64,
+    mode: String,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip)]
+    hasher: Option<Sha256>,
+    #[serde(skip)]
+    data: Option<(Content, Vec<u8>)>,
+}
+
+impl Record {
+    pub fn new(
+        target: &Target,
+        file: &str,
+        stat: &stat,
+        link: Option<&Link>,
+        content: Option<Content>,
+    ) -> Self {
+        let kind = match link {
+            Some(Link::Hardlink(_)) => "hardlink",
+            _ => entry_type(stat.st_mode),
+        };
+        let regular = kind == "file";
+
+        Self {
+            package: target.name.clone(),
+            version: target.version.clone(),
+            repo: target.repo.clone(),
+            path: file.trim_end_matches('/').to_string(),
+            dest: None,
+            kind,
+            size: stat.st_size,
+            mode: format!("{:04o}", stat.st_mode & 0o7777),
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            mtime: stat.st_mtime,
+            link: link.map(|link| match link {
+                Link::Symlink(dest) | Link::Hardlink(dest) => dest.clone(),
+            }),
+            sha256: None,
+            encoding: None,
+            content: None,
+            hasher: regular.then(Sha256::new),
+            data: content.filter(|_| regular).map(|c| (c, Vec::new())),
+        }
+    }
+
+    /// Set where the entry is being extracted or installed to
+    pub fn set_dest(&mut self, dest: &str) {
+        self.dest = Some(dest.trim_end_matches('/').to_string());
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+        if let Some((_, buf)) = &mut self.data {
+            buf.extend_from_slice(data);
+        }
+    }
+
+    /// Fill in the hash and content once all of the entry has been read and print it
+    pub fn finish(mut self, args: &Args) -> Result<()> {
+        if let Some(hasher) = self.hasher.take() {
+            self.sha256 = Some(format!("{:x}", hasher.finalize()));
+        }
+
+        if let Some((content, data)) = self.data.take() {
+            // content that is not valid utf-8 can't be represented in json without mangling it
+            let (encoding, content) = match (content, String::from_utf8(data)) {
+                (Content::Utf8, Ok(text)) => ("utf-8", text),
+                (Content::Base64, Ok(text)) => ("base64", base64(text.as_bytes())),
+                (_, Err(e)) => ("base64", base64(e.as_bytes())),
+            };
+            self.encoding = Some(encoding);
+            self.content = Some(content);
+        }
+
+        match &args.format_string {
+            Some(template) => {
+                let line = args::expand_template(template, |name| self.field(name))
+                    .map_err(|e| anyhow!(e))?;
+                writeln!(io::stdout().lock(), "{}", line)?;
+                Ok(())
+            }
+            None => emit(args.format, &self),
+        }
+    }
+
+    /// The value of a --format-string placeholder, missing values are printed as -
+    fn field(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "pkg" => Some(self.package.clone()),
+            "version" => Some(self.version.clone()),
+            "repo" => self.repo.clone(),
+            "path" => Some(self.path.clone()),
+            "name" => self.path.rsplit('/').next().map(str::to_string),
+            "dest" => self.dest.clone(),
+            "type" => Some(self.kind.to_string()),
+            "size" => Some(human_size(self.size)),
+            "bytes" => Some(self.size.to_string()),
+            "mode" => Some(self.mode.clone()),
+            "uid" => Some(self.uid.to_string()),
+            "gid" => Some(self.gid.to_string()),
+            "mtime" => Some(self.mtime.to_string()),
+            "sha256" => self.sha256.clone(),
+            "link" => self.link.clone(),
+            _ => return None,
+        };
+        Some(value.unwrap_or_else(|| "-".to_string()))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    Download { file: &'a str, result: &'a str },
+    Error { message: String },
+}
+
+/// Write a structured event to stderr, where the text messages would otherwise go
+pub fn event(event: &Event) {
+    let mut stderr = io::stderr().lock();
+    if serde_json::to_writer(&mut stderr, event).is_ok() {
+        let _ = writeln!(stderr);
+    }
+}
+
+/// Print an entry to stdout, as an element of an array for json or a line of its own for jsonl
+pub fn emit<T: Serialize>(format: Format, value: &T) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    match format {
+        Format::Json => {
+            match EMITTED.swap(true, Ordering::SeqCst) {
+                false => writeln!(stdout, "[")?,
+                true => writeln!(stdout, ",")?,
+            }
+            serde_json::to_writer_pretty(&mut stdout, value)?;
+        }
+        Format::Jsonl | Format::Text => {
+            serde_json::to_writer(&mut stdout, value)?;
+            writeln!(stdout)?;
+        }
+    }
+    Ok(())
+}
+
+/// Close the array started by the first entry printed with --format json
+pub fn finish(format: Format) -> Result<()> {
+    if let Format::Json = format {
+        let mut stdout = io::stdout().lock();
+        match EMITTED.load(Ordering::SeqCst) {
+            true => writeln!(stdout, "\n]")?,
+            false => writeln!(stdout, "[]")?,
+        }
+    }
+    Ok(())
+}
+
+/// Close the array if anything was printed, so the entries before an error can still be parsed
+pub fn abort(format: Format) {
+    if let Format::Json = format {
+        if EMITTED.load(Ordering::SeqCst) {
+            let _ = writeln!(io::stdout(), "\n]");
+        }
+    }
+}
+
+/// Format a size the way ls -h does, e.g. 2.6K
+fn human_size(size: i64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T"];
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match size < 10.0 {
+        true => format!("{:.1}{}", size, UNITS[unit]),
+        false => format!("{:.0}{}", size, UNITS[unit]),
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_known_answers() {
+        // the test vectors from RFC 4648
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(base64(input.as_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn base64_binary() {
+        assert_eq!(base64(&[0x00, 0xff, 0xfe, 0x80]), "AP/+gA==");
+        assert_eq!(base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+}
@@ -0,0 +1,284 @@
+use crate::args::Args;
+use crate::is_binary;
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::mem::take;
+
+// longer lines are only searched up to this length so a file without newlines can't use up memory
+const MAX_LINE: usize = 1024 * 1024;
+
+/// Searches the content of files as they are streamed from the archive
+///
+/// Data is split into lines as it arrives, with the unfinished last line of a chunk kept until
+/// the next one so matches are not missed at chunk boundaries. Each chunk is only scanned once.
+pub struct Grep<W: Write> {
+    regex: Regex,
+    out: W,
+    files_with_matches: bool,
+    before: usize,
+    after: usize,
+    // whether any line matched, for the exit status
+    matched: bool,
+    // whether a group of lines has been printed, so the next one can be separated with --
+    printed: bool,
+    // the file being searched, printed before each line as pkg:path
+    name: String,
+    partial: Vec<u8>,
+    line: usize,
+    // lines kept for --before-context
+    context: VecDeque<(usize, Vec<u8>)>,
+    // lines of --after-context still to print
+    trailing: usize,
+    last_printed: Option<usize>,
+    binary: Option<bool>,
+    done: bool,
+}
+
+impl<W: Write> Grep<W> {
+    /// Compile the --grep pattern up front so a bad regex is reported before downloading anything
+    pub fn new(args: &Args, pattern: &str, out: W) -> Result<Self> {
+        let regex = Regex::new(pattern).with_context(|| format!("invalid regex '{}'", pattern))?;
+        Ok(Self {
+            regex,
+            out,
+            files_with_matches: args.list,
+            before: args.before_context.or(args.context).unwrap_or(0),
+            after: args.after_context.or(args.context).unwrap_or(0),
+            matched: false,
+            printed: false,
+            name: String::new(),
+            partial: Vec::new(),
+            line: 0,
+            context: VecDeque::new(),
+            trailing: 0,
+            last_printed: None,
+            binary: None,
+            done: false,
+        })
+    }
+
+    pub fn matched(&self) -> bool {
+        self.matched
+    }
+
+    /// Start searching a file, name is printed before each line, as pkg:path
+    pub fn start(&mut self, name: String) {
+        self.name = name;
+        self.partial.clear();
+        self.line = 0;
+        self.context.clear();
+        self.trailing = 0;
+        self.last_printed = None;
+        self.binary = None;
+        self.done = false;
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        if self.binary.is_none() {
+            self.binary = Some(is_binary(data));
+        }
+
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            match self.partial.is_empty() {
+                true => self.search(&rest[..end.min(MAX_LINE)])?,
+                false => {
+                    self.push(&rest[..end]);
+                    let line = take(&mut self.partial);
+                    self.search(&line)?;
+                }
+            }
+            rest = &rest[end + 1..];
+            if self.done {
+                return Ok(());
+            }
+        }
+
+        self.push(rest);
+        Ok(())
+    }
+
+    /// Add to the unfinished line, dropping anything past MAX_LINE
+    fn push(&mut self, data: &[u8]) {
+        let room = MAX_LINE.saturating_sub(self.partial.len());
+        self.partial
+            .extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    /// Search the last line if the file does not end in a newline
+    pub fn finish(&mut self) -> Result<()> {
+        let buf = take(&mut self.partial);
+        if !buf.is_empty() && !self.done {
+            self.search(&buf)?;
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    fn search(&mut self, line: &[u8]) -> Result<()> {
+        self.line += 1;
+
+        if self.regex.is_match(line) {
+            self.matched = true;
+
+            if self.files_with_matches {
+                writeln!(self.out, "{}", self.name)?;
+                self.done = true;
+            } else if self.binary == Some(true) {
+                writeln!(self.out, "binary file {} matches", self.name)?;
+                self.done = true;
+            } else {
+                for (n, before) in take(&mut self.context) {
+                    self.print(n, '-', &before)?;
+                }
+                self.print(self.line, ':', line)?;
+                self.trailing = self.after;
+            }
+        } else if self.trailing > 0 {
+            self.trailing -= 1;
+            self.print(self.line, '-', line)?;
+        } else if self.before > 0 {
+            if self.context.len() == self.before {
+                self.context.pop_front();
+            }
+            self.context.push_back((self.line, line.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    /// Print a line as pkg:path:n:text for matches and pkg:path-n-text for context, like grep
+    fn print(&mut self, n: usize, sep: char, line: &[u8]) -> Result<()> {
+        let gap = self.last_printed != Some(n - 1);
+        let context = self.before > 0 || self.after > 0;
+        if gap && context && self.printed {
+            writeln!(self.out, "--")?;
+        }
+        self.printed = true;
+        self.last_printed = Some(n);
+
+        write!(self.out, "{}{}{}{}", self.name, sep, n, sep)?;
+        self.out.write_all(line)?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn grep(opts: &[&str], files: &[(&str, &[&[u8]])]) -> (String, bool) {
+        let args = Args::parse_from(["paccat"].iter().chain(opts));
+        let mut grep = Grep::new(&args, args.grep.as_deref().unwrap(), Vec::new()).unwrap();
+        for (name, chunks) in files {
+            grep.start(name.to_string());
+            for chunk in *chunks {
+                grep.update(chunk).unwrap();
+            }
+            grep.finish().unwrap();
+        }
+        (String::from_utf8(grep.out).unwrap(), grep.matched)
+    }
+
+    #[test]
+    fn match_across_chunks() {
+        let chunks: &[&[u8]] = &[b"one\nfo", b"o", b"bar\nthree"];
+        let (out, matched) = grep(&["--grep", "foobar"], &[("pkg:file", chunks)]);
+        assert_eq!(out, "pkg:file:2:foobar\n");
+        assert!(matched);
+    }
+
+    #[test]
+    fn last_line_without_newline() {
+        let chunks: &[&[u8]] = &[b"one\nfoo"];
+        let (out, _) = grep(&["--grep", "foo$"], &[("pkg:file", chunks)]);
+        assert_eq!(out, "pkg:file:2:foo\n");
+    }
+
+    #[test]
+    fn no_match() {
+        let chunks: &[&[u8]] = &[b"one\ntwo\n"];
+        assert_eq!(
+            grep(&["--grep", "three"], &[("pkg:file", chunks)]),
+            (String::new(), false)
+        );
+    }
+
+    #[test]
+    fn long_line_is_truncated() {
+        let mut first = vec![b'a'; MAX_LINE - 1];
+        first.push(b'x');
+        let second = [b"y".repeat(10), b"\n".to_vec()].concat();
+        let chunks: &[&[u8]] = &[&first, &second];
+
+        let (out, matched) = grep(&["--grep", "y"], &[("pkg:file", chunks)]);
+        assert_eq!((out, matched), (String::new(), false));
+
+        let (out, matched) = grep(&["--grep", "ax$"], &[("pkg:file", chunks)]);
+        assert!(matched);
+        assert_eq!(out.len(), "pkg:file:1:".len() + MAX_LINE + 1);
+    }
+
+    #[test]
+    fn long_line_in_one_chunk_is_truncated() {
+        let line = [vec![b'a'; MAX_LINE], b"y\n".to_vec()].concat();
+        let chunks: &[&[u8]] = &[&line];
+        assert_eq!(
+            grep(&["--grep", "y"], &[("pkg:file", chunks)]),
+            (String::new(), false)
+        );
+    }
+
+    #[test]
+    fn context_separators() {
+        let chunks: &[&[u8]] = &[b"a\nmatch\nb\nc\nd\ne\nmatch\nf\n"];
+        let (out, _) = grep(&["--grep", "match", "-C", "1"], &[("pkg:file", chunks)]);
+        assert_eq!(
+            out,
+            "pkg:file-1-a\npkg:file:2:match\npkg:file-3-b\n--\n\
+             pkg:file-6-e\npkg:file:7:match\npkg:file-8-f\n"
+        );
+    }
+
+    #[test]
+    fn adjacent_context_is_not_separated() {
+        let chunks: &[&[u8]] = &[b"match\na\nmatch\n"];
+        let (out, _) = grep(&["--grep", "match", "-A", "1"], &[("pkg:file", chunks)]);
+        assert_eq!(out, "pkg:file:1:match\npkg:file-2-a\npkg:file:3:match\n");
+    }
+
+    #[test]
+    fn before_context_separated_between_files() {
+        let first: &[&[u8]] = &[b"a\nmatch\n"];
+        let second: &[&[u8]] = &[b"b\nmatch\n"];
+        let (out, _) = grep(
+            &["--grep", "match", "-B", "1"],
+            &[("pkg:one", first), ("pkg:two", second)],
+        );
+        assert_eq!(
+            out,
+            "pkg:one-1-a\npkg:one:2:match\n--\npkg:two-1-b\npkg:two:2:match\n"
+        );
+    }
+
+    #[test]
+    fn no_separator_without_context() {
+        let chunks: &[&[u8]] = &[b"match\na\nb\nmatch\n"];
+        let (out, _) = grep(&["--grep", "match"], &[("pkg:file", chunks)]);
+        assert_eq!(out, "pkg:file:1:match\npkg:file:4:match\n");
+    }
+
+    #[test]
+    fn files_with_matches() {
+        let chunks: &[&[u8]] = &[b"match\nmatch\n"];
+        let (out, _) = grep(&["--grep", "match", "-l"], &[("pkg:file", chunks)]);
+        assert_eq!(out, "pkg:file\n");
+    }
+}
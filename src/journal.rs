@@ -0,0 +1,438 @@
+use crate::atomic::{self, AtomicFile};
+use crate::dest::{self, Dest};
+use crate::entry_type;
+use anyhow::{bail, ensure, Context, Result};
+use nix::libc;
+use nix::sys::stat::SFlag;
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{fchown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const JOURNAL_DIR: &str = "var/lib/paccat/journal";
+
+#[derive(Serialize, Deserialize)]
+struct Original {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    // the saved content of a file, or the target of a symlink
+    file: Option<String>,
+    link: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: String,
+    package: String,
+    original: Option<Original>,
+    // sha256 of the file that was installed, or the type of anything else
+    installed: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    id: u64,
+    time: u64,
+    undone: bool,
+    entries: Vec<Entry>,
+}
+
+/// Records the files overwritten by an install so they can be restored with --undo
+pub struct Journal {
+    root: String,
+    dir: Option<PathBuf>,
+    record: Record,
+}
+
+impl Journal {
+    pub fn new(root: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            root: root.to_string(),
+            dir: None,
+            record: Record {
+                id: 0,
+                time,
+                undone: false,
+                entries: Vec::new(),
+            },
+        }
+    }
+
+    fn dir(&mut self) -> Result<PathBuf> {
+        if let Some(dir) = &self.dir {
+            return Ok(dir.clone());
+        }
+
+        let root = create_journal_dir(&self.root)?;
+
+        let mut id = list_ids(&root)?.last().copied().unwrap_or(0) + 1;
+        let dir = loop {
+            let dir = root.join(id.to_string());
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => id += 1,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to mkdir {}", dir.display()))
+                }
+            }
+        };
+
+        self.record.id = id;
+        self.dir = Some(dir.clone());
+        Ok(dir)
+    }
+
+    /// Save the current state of a file before it is overwritten
+    ///
+    /// A path written more than once in the same operation keeps a single entry holding what was
+    /// there before the first write, which is what --undo has to put back.
+    pub fn record(&mut self, path: &str, package: &str) -> Result<()> {
+        if let Some(entry) = self.record.entries.iter_mut().find(|e| e.path == path) {
+            entry.package = package.to_string();
+            entry.installed = None;
+            return self.save();
+        }
+
+        let dir = self.dir()?;
+        let dest = Dest::open(&self.root, path, true)?;
+
+        let original = match dest.stat()? {
+            Some(stat) if dest::is_type(&stat, SFlag::S_IFREG) => {
+                let mut src = dest
+                    .open_file()?
+                    .with_context(|| format!("failed to open {}", path))?;
+                let file = self.record.entries.len().to_string();
+                let saved = dir.join(&file);
+                let mut out = File::options()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&saved)
+                    .with_context(|| format!("failed to create {}", saved.display()))?;
+                io::copy(&mut src, &mut out)
+                    .with_context(|| format!("failed to save {} to the journal", path))?;
+                Some(Original {
+                    mode: stat.st_mode & 0o7777,
+                    uid: stat.st_uid,
+                    gid: stat.st_gid,
+                    mtime: stat.st_mtime,
+                    file: Some(file),
+                    link: None,
+                })
+            }
+            Some(stat) if dest::is_type(&stat, SFlag::S_IFLNK) => Some(Original {
+                mode: stat.st_mode & 0o7777,
+                uid: stat.st_uid,
+                gid: stat.st_gid,
+                mtime: stat.st_mtime,
+                file: None,
+                link: Some(dest.read_link()?),
+            }),
+            _ => None,
+        };
+
+        self.record.entries.push(Entry {
+            path: path.to_string(),
+            package: package.to_string(),
+            original,
+            installed: None,
+        });
+        self.save()
+    }
+
+    /// Note the content that was installed so --undo can tell if the file changed since
+    pub fn installed(&mut self, path: &str) -> Result<()> {
+        let hash = hash_file(&Dest::open(&self.root, path, false)?)?;
+        if let Some(entry) = self.record.entries.iter_mut().find(|e| e.path == path) {
+            entry.installed = hash;
+        }
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let dir = self.dir()?;
+        save_record(&dir, &self.record)
+    }
+}
+
+fn save_record(dir: &Path, record: &Record) -> Result<()> {
+    let path = dir.join("journal.json");
+    let mut file = AtomicFile::new(&path, 0o600)?;
+    serde_json::to_writer_pretty(&mut file, record)?;
+    file.commit()
+}
+
+fn load_record(dir: &Path) -> Result<Record> {
+    let path = dir.join("journal.json");
+    check_private(dir)?;
+    check_private(&path)?;
+    let file = File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+    let record = serde_json::from_reader(file)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    Ok(record)
+}
+
+fn list_ids(root: &Path) -> Result<Vec<u64>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", root.display())),
+    };
+    check_private(root)?;
+
+    let mut ids = entries
+        .flatten()
+        .filter(|e| e.path().join("journal.json").exists())
+        .filter_map(|e| e.file_name().to_str()?.parse().ok())
+        .collect::<Vec<u64>>();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Create the journal directory beneath root, private to root whatever the umask
+///
+/// The journal decides what --undo writes where as root, so nobody else may be able to change it.
+fn create_journal_dir(root: &str) -> Result<PathBuf> {
+    let mut dir = Path::new(root).join("var/lib");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(&dir)
+        .with_context(|| format!("failed to mkdir {}", dir.display()))?;
+
+    for name in ["paccat", "journal"] {
+        dir.push(name);
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e).with_context(|| format!("failed to mkdir {}", dir.display())),
+        }
+
+        check_private(&dir)?;
+        ensure!(
+            dir.is_dir(),
+            "refusing to use {}: not a directory",
+            dir.display()
+        );
+    }
+
+    Ok(dir)
+}
+
+/// Refuse to trust part of the journal that someone other than its owner could have written
+fn check_private(path: &Path) -> Result<()> {
+    let meta = path
+        .symlink_metadata()
+        .with_context(|| format!("failed to stat {}", path.display()))?;
+    ensure!(
+        !meta.file_type().is_symlink(),
+        "refusing to use {}: it is a symlink",
+        path.display()
+    );
+    ensure!(
+        meta.uid() == Uid::effective().as_raw(),
+        "refusing to use {}: owned by uid {}",
+        path.display(),
+        meta.uid()
+    );
+    ensure!(
+        meta.mode() & 0o022 == 0,
+        "refusing to use {}: writable by other users",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Hash a file without following it if it has been replaced by a symlink
+fn hash_file(dest: &Dest) -> Result<Option<String>> {
+    let stat = match dest.stat()? {
+        Some(stat) => stat,
+        None => return Ok(None),
+    };
+    if dest::is_type(&stat, SFlag::S_IFLNK) {
+        return Ok(Some(format!("symlink to {}", dest.read_link()?)));
+    }
+    // anything that replaced the file will never match the hash of what was installed
+    if !dest::is_type(&stat, SFlag::S_IFREG) {
+        return Ok(Some(entry_type(stat.st_mode).to_string()));
+    }
+
+    let mut file = dest
+        .open_file()?
+        .with_context(|| format!("failed to open {}", dest.path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+fn format_time(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    let mut buf = [0u8; 64];
+
+    let len = unsafe {
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return secs.to_string();
+        }
+        libc::strftime(
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            c"%Y-%m-%d %H:%M:%S".as_ptr(),
+            &tm,
+        )
+    };
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+pub fn history(root: &str) -> Result<()> {
+    let root = Path::new(root).join(JOURNAL_DIR);
+    let mut stdout = io::stdout().lock();
+
+    for id in list_ids(&root)? {
+        let record = load_record(&root.join(id.to_string()))?;
+        let mut packages = record
+            .entries
+            .iter()
+            .map(|e| e.package.as_str())
+            .collect::<Vec<_>>();
+        packages.dedup();
+
+        write!(
+            stdout,
+            "{} {} {}",
+            record.id,
+            format_time(record.time),
+            packages.join(" ")
+        )?;
+        match record.undone {
+            true => writeln!(stdout, " (undone)")?,
+            false => writeln!(stdout)?,
+        }
+        for entry in &record.entries {
+            writeln!(stdout, "    {}", entry.path)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn undo(install_root: &str, id: Option<u64>) -> Result<bool> {
+    let root = Path::new(install_root).join(JOURNAL_DIR);
+    let ids = list_ids(&root)?;
+
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let mut last = None;
+            for &id in ids.iter().rev() {
+                if !load_record(&root.join(id.to_string()))?.undone {
+                    last = Some(id);
+                    break;
+                }
+            }
+            last.context("nothing to undo")?
+        }
+    };
+
+    if !ids.contains(&id) {
+        bail!("no journal entry with id {}", id);
+    }
+
+    let dir = root.join(id.to_string());
+    let mut record = load_record(&dir)?;
+    let mut skipped = false;
+
+    if record.undone {
+        bail!("journal entry {} has already been undone", id);
+    }
+
+    for entry in record.entries.iter().rev() {
+        // refuses paths outside of the root and parents that have become symlinks
+        let dest = Dest::open(install_root, &entry.path, true)?;
+
+        if hash_file(&dest)? != entry.installed {
+            writeln!(
+                io::stderr(),
+                "warning: {} has changed since it was installed, skipping",
+                entry.path
+            )?;
+            skipped = true;
+            continue;
+        }
+
+        match &entry.original {
+            Some(Original {
+                link: Some(link),
+                uid,
+                gid,
+                ..
+            }) => {
+                atomic::symlink(&dest, link)?;
+                if Uid::current().is_root() {
+                    dest.chown(*uid, *gid)?;
+                }
+                writeln!(io::stdout(), "restored {}", entry.path)?;
+            }
+            Some(original) => {
+                let file = original
+                    .file
+                    .as_deref()
+                    .with_context(|| format!("nothing saved for {}", entry.path))?;
+                let saved = dir.join(file);
+                check_private(&saved)?;
+                let mut src = File::open(&saved)
+                    .with_context(|| format!("failed to open {}", saved.display()))?;
+                let mut file = AtomicFile::create(dest, original.mode)?;
+                io::copy(&mut src, &mut file)
+                    .with_context(|| format!("failed to write {}", entry.path))?;
+
+                let out = file.file();
+                out.set_permissions(fs::Permissions::from_mode(original.mode))
+                    .with_context(|| format!("failed to chmod {}", entry.path))?;
+                if Uid::current().is_root() {
+                    fchown(out, Some(original.uid), Some(original.gid))
+                        .with_context(|| format!("failed to chown {}", entry.path))?;
+                }
+                let mtime = UNIX_EPOCH + Duration::from_secs(original.mtime.max(0) as u64);
+                out.set_modified(mtime)
+                    .with_context(|| format!("failed to set mtime of {}", entry.path))?;
+
+                file.commit()?;
+                writeln!(io::stdout(), "restored {}", entry.path)?;
+            }
+            None => {
+                let is_dir = dest
+                    .stat()?
+                    .is_some_and(|stat| dest::is_type(&stat, SFlag::S_IFDIR));
+                // directories that were created are left if something else has been put in them
+                if !dest.remove(is_dir)? {
+                    writeln!(
+                        io::stderr(),
+                        "warning: {} is not empty, skipping",
+                        entry.path
+                    )?;
+                    skipped = true;
+                    continue;
+                }
+                writeln!(io::stdout(), "removed {}", entry.path)?;
+            }
+        }
+    }
+
+    record.undone = true;
+    save_record(&dir, &record)?;
+
+    Ok(skipped)
+}
@@ -0,0 +1,77 @@
+use crate::args::Args;
+use anyhow::{bail, Result};
+use compress_tools::ArchiveContents;
+
+/// Caps on what is read out of a package so a decompression bomb can not exhaust memory or disk
+///
+/// Sizes are counted from the decompressed data as the sizes in the entry headers can not be
+/// trusted.
+pub struct Limits {
+    max_file_size: Option<u64>,
+    max_total_size: Option<u64>,
+    max_entries: Option<u64>,
+    file: String,
+    file_size: u64,
+    total_size: u64,
+    entries: u64,
+}
+
+impl Limits {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            max_file_size: args.max_file_size,
+            max_total_size: args.max_total_size,
+            max_entries: args.max_entries,
+            file: String::new(),
+            file_size: 0,
+            total_size: 0,
+            entries: 0,
+        }
+    }
+
+    pub fn check(&mut self, content: &ArchiveContents) -> Result<()> {
+        match content {
+            ArchiveContents::StartOfEntry(file, stat) => {
+                self.entries += 1;
+                if let Some(max) = self.max_entries {
+                    if self.entries > max {
+                        bail!("package has more than {} entries (--max-entries)", max);
+                    }
+                }
+
+                self.file.clone_from(file);
+                self.file_size = 0;
+                self.check_file(stat.st_size.max(0) as u64)
+            }
+            ArchiveContents::DataChunk(data) => {
+                self.file_size += data.len() as u64;
+                self.total_size += data.len() as u64;
+                self.check_file(self.file_size)?;
+
+                if let Some(max) = self.max_total_size {
+                    if self.total_size > max {
+                        bail!(
+                            "package is larger than {} bytes uncompressed (--max-total-size)",
+                            max
+                        );
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_file(&self, size: u64) -> Result<()> {
+        if let Some(max) = self.max_file_size {
+            if size > max {
+                bail!(
+                    "{} is larger than {} bytes (--max-file-size)",
+                    self.file,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+}
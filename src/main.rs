@@ -1,35 +1,158 @@
-use crate::args::Args;
-use crate::pacman::{alpm_init, get_dbpkg, get_download_url};
+use crate::archive::{entry_link, load_metadata, EntryMeta, Link};
+use crate::args::{Args, Collision, Format};
+use crate::atomic::AtomicFile;
+use crate::dest::Dest;
+use crate::format::Record;
+use crate::grep::Grep;
+use crate::journal::Journal;
+use crate::limits::Limits;
+use crate::pacman::{
+    alpm_init, fetch_packages, find_owner, get_dbpkg, get_download_url, get_installed_pkgfile,
+    match_patterns,
+};
+use crate::restore::Restore;
+use crate::sandbox::Entries;
 use alpm::{Alpm, Package};
 use alpm_utils::DbListExt;
 use anyhow::{bail, ensure, Context, Error, Result};
 use clap::Parser;
 use compress_tools::{ArchiveContents, ArchiveIterator};
+use nix::libc::stat;
 use nix::sys::stat::{umask, Mode, SFlag};
 use nix::unistd::{isatty, Uid};
 use pacman::verify_packages;
 use regex::RegexSet;
-use std::fs::{create_dir_all, File};
-use std::io::{self, stderr, stdin, BufRead, ErrorKind, Read, Seek, Stdout, StdoutLock, Write};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, Permissions};
+use std::io::{self, stderr, stdin, BufRead, ErrorKind, Stdout, StdoutLock, Write};
 use std::mem::take;
-use std::os::unix::fs::fchown;
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process::{Child, ChildStdin, Command, Stdio};
+use std::slice;
 
+mod archive;
 mod args;
+mod atomic;
+mod check;
+mod compare;
+mod dest;
+mod diff;
+mod format;
+mod grep;
+mod journal;
+mod limits;
+mod merge;
 mod pacman;
+mod preserve;
+mod restore;
+mod sandbox;
+mod upgrade;
 
 #[derive(Default)]
 enum Output<'a> {
     Stdout(StdoutLock<'a>),
+    // stdout with each line prefixed for --with-filename, and whether a line has been started
+    Prefixed(StdoutLock<'a>, String, bool),
     Bat(Child, ChildStdin),
-    File(File),
+    File(AtomicFile),
+    Diff(Vec<u8>),
+    Restore(Restore),
+    Preview(stat, Vec<u8>),
+    // the file is searched by OutputContext::grep
+    Grep,
     #[default]
     None,
 }
 
+/// The entries of a package, parsed in a sandbox when running as root
+enum Archive {
+    Direct(ArchiveIterator<File>),
+    Sandboxed(Entries),
+}
+
+impl Iterator for Archive {
+    type Item = ArchiveContents;
+
+    fn next(&mut self) -> Option<ArchiveContents> {
+        match self {
+            Archive::Direct(archive) => archive.next(),
+            Archive::Sandboxed(entries) => entries.next(),
+        }
+    }
+}
+
+/// The package a set of files is being read from
+#[derive(Default)]
+struct Target {
+    path: String,
+    name: String,
+    version: String,
+    repo: Option<String>,
+    backup: Vec<String>,
+}
+
+impl Target {
+    fn load(alpm: &Alpm, path: &str) -> Result<Self> {
+        let pkg = sandbox::load_package(alpm, path)?;
+        let repo = alpm
+            .syncdbs()
+            .iter()
+            .find(|db| {
+                db.pkg(pkg.name.as_str())
+                    .is_ok_and(|p| p.version().as_str() == pkg.version)
+            })
+            .map(|db| db.name().to_string());
+        Ok(Self {
+            path: path.to_string(),
+            name: pkg.name,
+            version: pkg.version,
+            repo,
+            backup: pkg.backup,
+        })
+    }
+}
+
+/// Output state that carries over from one file and package to the next
+struct OutputContext {
+    color: bool,
+    grep: Option<Grep<Stdout>>,
+    // whether a --headers banner has been printed, so the next one is separated by a blank line
+    header: bool,
+}
+
+/// Records kept of the files written by --install and --extract
+#[derive(Default)]
+struct InstallLog {
+    journal: Option<Journal>,
+    manifest: Option<File>,
+    extracted: HashSet<String>,
+    // archive path to where it was written, used as the source of hardlinks
+    written: HashMap<String, String>,
+}
+
+impl InstallLog {
+    fn add_manifest(&mut self, root: &str, path: &str) -> Result<()> {
+        if let Some(manifest) = &mut self.manifest {
+            let file = path.strip_prefix(root).unwrap_or(path);
+            let meta = Path::new(path)
+                .symlink_metadata()
+                .with_context(|| format!("failed to stat {}", path))?;
+            writeln!(
+                manifest,
+                "/{} {:04o} {}:{} {}",
+                file.trim_end_matches('/'),
+                meta.mode() & 0o7777,
+                meta.uid(),
+                meta.gid(),
+                meta.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(PartialEq, Eq)]
 enum EntryState {
     Skip,
@@ -56,14 +179,25 @@ impl Match {
         })
     }
 
+    /// Match every file, used when no files were given
+    fn all() -> Self {
+        Self {
+            exact_file: false,
+            with: MatchWith::All,
+            matched: Vec::new(),
+        }
+    }
+
     fn all_matched(&self) -> bool {
         match &self.with {
+            MatchWith::All => true,
             MatchWith::Regex(r) => r.len() == self.matched.len(),
             MatchWith::Files(f) => f.len() == self.matched.len(),
         }
     }
 
     fn is_match(&mut self, file: &str, match_once: bool) -> bool {
+        let file = file.trim_end_matches('/');
         let file = if !self.exact_file {
             file.rsplit('/').next().unwrap()
         } else {
@@ -75,6 +209,7 @@ impl Match {
         }
 
         match self.with {
+            MatchWith::All => true,
             MatchWith::Regex(ref mut r) => {
                 let mut new_match = false;
                 for m in r.matches(file) {
@@ -105,6 +240,7 @@ impl Match {
 
 #[derive(Debug)]
 enum MatchWith {
+    All,
     Regex(RegexSet),
     Files(Vec<String>),
 }
@@ -122,7 +258,17 @@ impl MatchWith {
     }
 }
 
-fn print_error(err: Error) {
+fn print_error(err: Error, format: Format) {
+    if !matches!(format, Format::Text) {
+        let message = err
+            .chain()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        format::event(&format::Event::Error { message });
+        return;
+    }
+
     let mut stderr = stderr();
     let _ = write!(stderr, "error");
     for link in err.chain() {
@@ -132,7 +278,9 @@ fn print_error(err: Error) {
 }
 
 fn main() {
-    match run() {
+    let args = args::Args::parse();
+    let format = args.format;
+    match run(args) {
         Ok(i) => std::process::exit(i),
         Err(e) => {
             if let Some(e) = e.downcast_ref::<io::Error>() {
@@ -140,7 +288,8 @@ fn main() {
                     std::process::exit(1);
                 }
             }
-            print_error(e);
+            format::abort(format);
+            print_error(e, format);
             std::process::exit(1);
         }
     }
@@ -163,31 +312,94 @@ fn read_stdin(values: &mut Vec<String>) -> Result<()> {
     Ok(())
 }
 
-fn run() -> Result<i32> {
-    let mut args = args::Args::parse();
+fn run(mut args: Args) -> Result<i32> {
     let stdout = io::stdout();
     let is_tty = isatty(stdout.as_raw_fd()).unwrap_or(false);
 
-    if !args.targets.is_empty() && args.files.is_empty() {
-        if args.filedb || args.localdb {
+    if args.pkgdiff {
+        if args.targets.len() != 2 {
+            bail!("--pkgdiff requires two targets (use -h for help)");
+        }
+        args.all = true;
+    } else if args.upgrade_preview {
+        args.diff = true;
+        args.all = true;
+    } else if args.check {
+        args.localdb = true;
+    } else if args.restore {
+        if args.targets.is_empty() {
+            bail!("no targets specified (use -h for help)");
+        }
+        args.localdb = true;
+        args.all = true;
+    } else if !args.targets.is_empty() && args.files.is_empty() {
+        if args.filedb || args.localdb || args.merge {
             args.files = args.targets.split_off(0);
         } else {
             args.files = args.targets.split_off(1);
         }
     }
 
-    if !args.localdb && !args.filedb && args.targets.is_empty() {
+    let grep = match args.grep.clone() {
+        Some(pattern) => {
+            args.all = true;
+            Some(Grep::new(&args, &pattern, io::stdout())?)
+        }
+        None => None,
+    };
+
+    // these look at every file of the package unless told which, but -F/-Q need files to search for
+    let every_file = args.files.is_empty()
+        && (args.pkgdiff || args.restore || (args.grep.is_some() && !args.localdb))
+        && !args.filedb;
+
+    if let Some(destdir) = &mut args.destdir {
+        *destdir = format!("{}/", destdir.trim_end_matches('/'));
+        args.install = true;
+    }
+
+    let journal_cmd = args.history || args.undo.is_some();
+    let no_targets =
+        args.localdb || args.filedb || args.upgrade_preview || args.merge || journal_cmd;
+    let no_files = args.upgrade_preview || args.check || journal_cmd;
+
+    if !no_targets && args.targets.is_empty() {
         bail!("no targets specified (use -h for help)");
     }
-    if args.files.is_empty() {
+    if !no_files && !every_file && args.files.is_empty() {
         bail!("no files specified (use -h for help)");
     }
+    if args.format_string.is_some() && !args.list && !args.extract && !args.install {
+        bail!("--format-string requires --list, --extract or --install");
+    }
+    if args.manifest.is_some() && !args.install {
+        bail!("--manifest requires --install or --destdir");
+    }
+    // these only print plain text, which would be mixed into the json
+    if !matches!(args.format, Format::Text) {
+        ensure!(
+            !args.restore,
+            "--format json/jsonl can not be used with --restore"
+        );
+        ensure!(
+            !args.dry_run || !(args.install || args.extract),
+            "--format json/jsonl can not be used with --dry-run"
+        );
+        ensure!(
+            !args.install || args.destdir.is_some() || args.noconfirm,
+            "--format json/jsonl with --install requires --noconfirm"
+        );
+    }
 
     read_stdin(&mut args.targets)?;
     read_stdin(&mut args.files)?;
 
     args.binary |= !is_tty;
-    args.binary |= args.extract || args.install;
+    args.binary |= args.extract || args.install || args.diff || args.restore;
+
+    // like head, name each file when more than one may be printed
+    let several = args.all || args.files.len() > 1 || args.targets.len() > 1;
+    args.headers |= is_tty && several && !args.no_headers;
 
     let color = match args.color {
         args::ColorWhen::Auto => is_tty,
@@ -201,37 +413,224 @@ fn run() -> Result<i32> {
         .map(|f| f.trim_start_matches('/').to_string())
         .collect::<Vec<_>>();
 
-    let mut matcher = Match::new(args.regex, files)?;
+    let other = match args.pkgdiff {
+        true => args.targets.pop(),
+        false => args.diff_with.clone(),
+    };
+
+    let mut matcher = match every_file {
+        true => Match::all(),
+        false => Match::new(args.regex, files.clone())?,
+    };
     let alpm = alpm_init(&args)?;
 
-    let pkgs = get_targets(&alpm, &args, &mut matcher)?;
+    if args.history {
+        journal::history(alpm.root())?;
+        return Ok(0);
+    }
+
+    if let Some(id) = args.undo {
+        let skipped = journal::undo(alpm.root(), id)?;
+        return Ok(skipped as i32);
+    }
+
+    if args.upgrade_preview {
+        let differ = upgrade::upgrade_preview(&alpm, &args, color)?;
+        return Ok(differ as i32);
+    }
 
-    if args.install {
+    if args.merge {
+        let conflict = merge::merge(&alpm, &args, &mut io::stdout())?;
+        return Ok(conflict as i32);
+    }
+
+    if args.check {
+        let altered = check::check(&alpm, &args)?;
+        return Ok(altered as i32);
+    }
+
+    let pkgs = get_targets(&alpm, &args, &args.targets, &mut matcher)?;
+
+    if let Some(target) = &other {
+        let mut other_matcher = match every_file {
+            true => Match::all(),
+            false => Match::new(args.regex, files)?,
+        };
+        let others = get_targets(&alpm, &args, slice::from_ref(target), &mut other_matcher)?;
+
+        let differ = if args.pkgdiff {
+            let mut old = BTreeMap::new();
+            let mut new = BTreeMap::new();
+
+            for pkg in pkgs {
+                diff::read_entries(&pkg, &mut matcher, &args, &mut old)?;
+            }
+            for pkg in others {
+                diff::read_entries(&pkg, &mut other_matcher, &args, &mut new)?;
+            }
+
+            diff::print_pkgdiff(&args.targets[0], target, &old, &new, &args, color)?
+        } else {
+            let mut old = BTreeMap::new();
+            let mut new = BTreeMap::new();
+
+            for pkg in pkgs {
+                diff::read_files(open_archive(&pkg)?, &mut matcher, &args, &mut old)?;
+            }
+            for pkg in others {
+                diff::read_files(open_archive(&pkg)?, &mut other_matcher, &args, &mut new)?;
+            }
+
+            diff::diff_files(&old, &new, color)?
+        };
+
+        return match matcher.all_matched() && !differ {
+            true => Ok(0),
+            false => Ok(1),
+        };
+    }
+
+    if args.install || args.restore {
         umask(Mode::empty());
     }
 
-    for pkg in pkgs {
-        let file = File::open(&pkg).with_context(|| format!("failed to open {}", pkg))?;
-        let archive = ArchiveIterator::from_read(file)?;
-        dump_files(archive, &mut matcher, &args, color, &alpm)?;
+    let mut ctx = OutputContext {
+        color,
+        grep,
+        header: false,
+    };
+
+    if args.install && args.destdir.is_none() && !args.dry_run && !args.noconfirm {
+        args.dry_run = true;
+        for pkg in &pkgs {
+            let target = Target::load(&alpm, pkg)?;
+            let archive = open_archive(pkg)?;
+            let log = &mut InstallLog::default();
+            dump_files(archive, &mut matcher, &args, &alpm, &target, log, &mut ctx)?;
+        }
+        args.dry_run = false;
+
+        if !matcher.matched.is_empty() && !confirm("Proceed with installation?")? {
+            format::finish(args.format)?;
+            return Ok(1);
+        }
+        matcher.matched.clear();
+    }
+
+    let mut changed = 0;
+    let mut log = InstallLog::default();
+
+    if args.install && !args.dry_run {
+        if args.destdir.is_none() {
+            log.journal = Some(Journal::new(alpm.root()));
+        }
+        if let Some(manifest) = &args.manifest {
+            // the umask has been cleared so give the mode explicitly
+            let file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o644)
+                .open(manifest)
+                .with_context(|| format!("failed to create {}", manifest))?;
+            log.manifest = Some(file);
+        }
+    }
+
+    for pkg in &pkgs {
+        let prefix = args.extract && matches!(args.collision, Collision::PrefixWithPackage);
+        let named =
+            args.headers || args.with_filename || args.grep.is_some() || prints_records(&args);
+        let target = match args.install || args.restore || prefix || named {
+            true => Target::load(&alpm, pkg)?,
+            false => Target {
+                path: pkg.clone(),
+                ..Default::default()
+            },
+        };
+        let archive = open_archive(pkg)?;
+        changed += dump_files(
+            archive,
+            &mut matcher,
+            &args,
+            &alpm,
+            &target,
+            &mut log,
+            &mut ctx,
+        )?;
+    }
+
+    format::finish(args.format)?;
+
+    if let Some(grep) = &ctx.grep {
+        return Ok(!grep.matched() as i32);
+    }
+
+    if args.restore {
+        match args.dry_run {
+            true => writeln!(stdout.lock(), "{} files would be restored", changed)?,
+            false => writeln!(stdout.lock(), "{} files restored", changed)?,
+        }
+        return Ok(0);
     }
 
-    match matcher.all_matched() {
+    match matcher.all_matched() && changed == 0 {
         true => Ok(0),
         false => Ok(1),
     }
 }
 
+fn confirm(question: &str) -> Result<bool> {
+    // also the case when targets were read from stdin with -
+    ensure!(
+        isatty(stdin().as_raw_fd()).unwrap_or(false),
+        "stdin is not a terminal (use --noconfirm)"
+    );
+
+    let mut stderr = stderr();
+    write!(stderr, ":: {} [Y/n] ", question)?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer)? == 0 {
+        writeln!(stderr)?;
+        return Ok(false);
+    }
+
+    let answer = answer.trim().to_lowercase();
+    Ok(answer.is_empty() || answer == "y" || answer == "yes")
+}
+
+fn open_archive(pkg: &str) -> Result<Archive> {
+    let file = File::open(pkg).with_context(|| format!("failed to open {}", pkg))?;
+    let archive = match sandbox::get() {
+        Some(sandbox) => Archive::Sandboxed(sandbox.open_archive(file)?),
+        None => Archive::Direct(ArchiveIterator::from_read(file)?),
+    };
+    Ok(archive)
+}
+
 fn open_output(
     output: &mut Output,
     stdout: &mut Stdout,
     filename: &str,
     use_bat: bool,
+    prefix: Option<String>,
 ) -> Result<()> {
     match (output, use_bat) {
-        (Output::File(_), _) => (),
+        (
+            Output::File(_)
+            | Output::Diff(_)
+            | Output::Restore(_)
+            | Output::Preview(..)
+            | Output::Grep,
+            _,
+        ) => (),
         (output @ Output::Bat(_, _), _)
-        | (output @ Output::None | output @ Output::Stdout(_), true) => {
+        | (
+            output @ Output::None | output @ Output::Stdout(_) | output @ Output::Prefixed(..),
+            true,
+        ) => {
             let mut child = Command::new("bat")
                 .arg("-pp")
                 .arg("--color=always")
@@ -243,117 +642,536 @@ fn open_output(
             let stdin = child.stdin.take().unwrap();
             *output = Output::Bat(child, stdin);
         }
-        (output @ Output::None | output @ Output::Stdout(_), false) => {
-            *output = Output::Stdout(stdout.lock())
+        (
+            output @ Output::None | output @ Output::Stdout(_) | output @ Output::Prefixed(..),
+            false,
+        ) => {
+            *output = match prefix {
+                Some(prefix) => Output::Prefixed(stdout.lock(), prefix, false),
+                None => Output::Stdout(stdout.lock()),
+            }
         }
     };
     Ok(())
 }
 
-fn close_outout(output: &mut Output) -> Result<()> {
-    if let Output::Bat(mut child, stdin) = take(output) {
-        drop(stdin);
-        let status = child.wait().context("failed to wait for bat")?;
-        ensure!(
-            status.success(),
-            "bat failed to run (exited {})",
-            status.code().unwrap_or(1),
-        );
+/// Print a banner naming the file before its content, separated from the previous file like head
+fn print_header(
+    stdout: &mut Stdout,
+    ctx: &mut OutputContext,
+    target: &Target,
+    file: &str,
+) -> Result<()> {
+    if ctx.header {
+        writeln!(stdout)?;
+    }
+    ctx.header = true;
+    match &target.repo {
+        Some(repo) => writeln!(stdout, "==> {}/{}:{} <==", repo, target.name, file)?,
+        None => writeln!(stdout, "==> {}:{} <==", target.name, file)?,
     }
     Ok(())
 }
 
-fn dump_files<R>(
-    archive: ArchiveIterator<R>,
+fn close_outout(output: &mut Output, ctx: &mut OutputContext) -> Result<()> {
+    match take(output) {
+        Output::Bat(mut child, stdin) => {
+            drop(stdin);
+            let status = child.wait().context("failed to wait for bat")?;
+            ensure!(
+                status.success(),
+                "bat failed to run (exited {})",
+                status.code().unwrap_or(1),
+            );
+        }
+        Output::File(file) => file.commit()?,
+        // end the last line so the next file's prefix starts on a line of its own
+        Output::Prefixed(mut stdout, _, true) => writeln!(stdout)?,
+        Output::Grep => {
+            if let Some(grep) = &mut ctx.grep {
+                grep.finish()?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn open_install(root: &str, path: &str, stat: &stat, install: bool) -> Result<AtomicFile> {
+    let dest = Dest::open(root, path, true)?;
+    // a symlink or anything else at the destination is replaced rather than followed
+    let existing = dest
+        .stat()?
+        .filter(|existing| dest::is_type(existing, SFlag::S_IFREG));
+
+    let extract_file = AtomicFile::create(dest, stat.st_mode)?;
+
+    // the file is replaced on commit so carry over the mode and owner of the file it replaces
+    let owner = match &existing {
+        Some(existing) => {
+            extract_file
+                .file()
+                .set_permissions(Permissions::from_mode(existing.st_mode & 0o7777))
+                .with_context(|| format!("failed to chmod {}", path))?;
+            Some((existing.st_uid, existing.st_gid))
+        }
+        None if install => Some((stat.st_uid, stat.st_gid)),
+        None => None,
+    };
+
+    if let (Some((uid, gid)), true) = (owner, Uid::current().is_root()) {
+        fchown(extract_file.file(), Some(uid), Some(gid))
+            .with_context(|| format!("failed to chown {}", path))?;
+    }
+
+    Ok(extract_file)
+}
+
+/// Where a file should be extracted to, applying the --collision policy to files that were
+/// already extracted in this run
+fn extract_path(
+    args: &Args,
+    target: &Target,
+    file: &str,
+    extracted: &mut HashSet<String>,
+) -> Result<String> {
+    let name = match args.preserve_paths {
+        true => file,
+        false => file.rsplit('/').next().unwrap(),
+    };
+    let mut path = match &args.output_dir {
+        Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), name),
+        None => name.to_string(),
+    };
+
+    if extracted.contains(&path) {
+        match args.collision {
+            Collision::Error => bail!(
+                "{} has already been extracted (use --collision to choose what to do)",
+                path
+            ),
+            Collision::Overwrite => (),
+            Collision::Rename => {
+                let mut n = 1;
+                while extracted.contains(&format!("{}.{}", path, n)) {
+                    n += 1;
+                }
+                path = format!("{}.{}", path, n);
+            }
+            Collision::PrefixWithPackage => {
+                path = match path.rsplit_once('/') {
+                    Some((dir, base)) => format!("{}/{}-{}", dir, target.name, base),
+                    None => format!("{}-{}", target.name, path),
+                };
+                ensure!(
+                    !extracted.contains(&path),
+                    "{} has already been extracted",
+                    path
+                );
+            }
+        }
+    }
+
+    extracted.insert(path.clone());
+    Ok(path)
+}
+
+/// Whether entries are printed as json or with --format-string rather than as plain paths
+fn prints_records(args: &Args) -> bool {
+    !matches!(args.format, Format::Text) || args.format_string.is_some()
+}
+
+/// Whether the files were already listed and warned about before asking for confirmation
+fn listed_before(args: &Args) -> bool {
+    args.install && args.destdir.is_none() && !args.dry_run && !args.noconfirm
+}
+
+/// The mode a file is written with
+///
+/// setuid, setgid and sticky bits are removed from files unless --allow-setuid is given,
+/// which is warned about when installing or restoring. World-writable files are reported, as
+/// are world-writable directories without the sticky bit.
+fn write_mode(args: &Args, path: &str, kind: SFlag, st_mode: u32) -> Result<u32> {
+    let warn = !listed_before(args);
+    let special = st_mode & 0o7000;
+    let mut st_mode = st_mode;
+
+    if kind == SFlag::S_IFREG && special != 0 && !args.allow_setuid {
+        if (args.install || args.restore) && warn {
+            writeln!(
+                stderr(),
+                "warning: {}: removing mode bits {:04o} (use --allow-setuid to keep them)",
+                path,
+                special
+            )?;
+        }
+        st_mode &= !0o7000;
+    }
+
+    let sticky_dir = kind == SFlag::S_IFDIR && st_mode & 0o1000 != 0;
+    if st_mode & 0o002 != 0 && !sticky_dir && warn {
+        writeln!(stderr(), "warning: {} is world-writable", path)?;
+    }
+
+    Ok(st_mode)
+}
+
+fn install_root<'a>(alpm: &'a Alpm, args: &'a Args) -> &'a str {
+    args.destdir.as_deref().unwrap_or(alpm.root())
+}
+
+/// The directory files are written beneath, symlinks below it are never followed
+fn write_root<'a>(alpm: &'a Alpm, args: &'a Args) -> &'a str {
+    match &args.output_dir {
+        _ if args.install => install_root(alpm, args),
+        _ if args.restore => alpm.root(),
+        Some(dir) => dir,
+        None => "",
+    }
+}
+
+/// Where a file should be installed, or None if it is in NoExtract
+///
+/// Files protected by NoUpgrade or the package's backup array are installed as .pacnew
+/// when they already exist. Files owned by another package are refused unless --force is given.
+/// None of this applies when installing to --destdir.
+fn install_path(
+    alpm: &Alpm,
+    args: &Args,
+    target: &Target,
+    file: &str,
+) -> Result<Option<(String, bool)>> {
+    let path = format!("{}{}", install_root(alpm, args), file);
+
+    if args.destdir.is_some() {
+        return Ok(Some((path, false)));
+    }
+
+    if let Some(owner) = find_owner(alpm, file) {
+        if owner.name() != target.name {
+            if !args.force {
+                bail!(
+                    "{}: {} exists in filesystem (owned by {})",
+                    target.name,
+                    path,
+                    owner.name()
+                );
+            }
+            if !listed_before(args) {
+                writeln!(
+                    stderr(),
+                    "warning: {}: {} is owned by {}",
+                    target.name,
+                    path,
+                    owner.name()
+                )?;
+            }
+        }
+    }
+
+    if args.force {
+        return Ok(Some((path, false)));
+    }
+    if match_patterns(alpm.noextracts(), file) {
+        return Ok(None);
+    }
+
+    let protected =
+        match_patterns(alpm.noupgrades(), file) || target.backup.iter().any(|b| b == file);
+    if protected && Path::new(&path).exists() {
+        return Ok(Some((format!("{}.pacnew", path), true)));
+    }
+
+    Ok(Some((path, false)))
+}
+
+fn dump_files<I>(
+    archive: I,
     matcher: &mut Match,
     args: &Args,
-    color: bool,
     alpm: &Alpm,
-) -> Result<()>
+    target: &Target,
+    log: &mut InstallLog,
+    ctx: &mut OutputContext,
+) -> Result<usize>
 where
-    R: Read + Seek,
+    I: Iterator<Item = ArchiveContents>,
 {
     let mut stdout = io::stdout();
     let mut output = Output::default();
     let mut state = EntryState::Skip;
     let mut filename = String::new();
+    let mut path = String::new();
+    let mut changed = 0;
+    let mut metadata: Option<HashMap<String, EntryMeta>> = None;
+    let mut attrs: Option<(stat, Option<EntryMeta>)> = None;
+    let mut follow = Vec::new();
+    let mut limits = Limits::new(args);
+    let mut record: Option<Record> = None;
+    let records = prints_records(args);
+    // with --grep, --list lists the files that match rather than every file
+    let list = args.list && args.grep.is_none();
 
+    let color = ctx.color;
     let use_bat = color
-        && !args.list
+        && !records
+        && !args.with_filename
+        && !list
+        && args.grep.is_none()
         && !args.extract
         && !args.install
+        && !args.diff
+        && !args.restore
         && Command::new("bat").arg("-h").output().is_ok();
 
     for content in archive {
+        // a partially written file is removed when its output is dropped on error
+        limits.check(&content)?;
         match content {
-            ArchiveContents::StartOfEntry(mut file, stat) => {
+            ArchiveContents::StartOfEntry(file, mut stat) => {
                 let mode = Mode::from_bits_truncate(stat.st_mode);
                 let kind = SFlag::from_bits_truncate(stat.st_mode);
+                let writes = args.extract || args.install;
 
-                if kind != SFlag::S_IFREG {
+                let wanted = match kind {
+                    SFlag::S_IFREG => true,
+                    SFlag::S_IFLNK => !args.diff && !args.restore,
+                    SFlag::S_IFDIR => list || args.install || (args.extract && args.preserve_paths),
+                    _ => false,
+                };
+                if !wanted {
                     continue;
                 }
 
-                if args.executable && !mode.contains(Mode::S_IXUSR) {
+                if args.executable && (kind == SFlag::S_IFDIR || !mode.contains(Mode::S_IXUSR)) {
                     continue;
                 }
 
                 filename = file.rsplit('/').next().unwrap().to_string();
 
-                if matcher.is_match(&file, !args.all) {
-                    if args.list || args.extract || args.install {
-                        writeln!(stdout, "{}", file)?;
+                if args.restore && !args.include_backup && target.backup.contains(&file) {
+                    continue;
+                }
+
+                if !matcher.is_match(&file, !args.all) {
+                    continue;
+                }
 
-                        if args.extract || args.install {
-                            state = EntryState::FirstChunk;
-                            let open_file = if args.install {
-                                file.insert_str(0, alpm.root());
-                                Path::new(&file)
-                            } else {
-                                Path::new(&filename)
-                            };
-
-                            let exists = !args.install || open_file.exists();
-
-                            if !exists {
-                                if let Some(parent) = open_file.parent() {
-                                    create_dir_all(parent).with_context(|| {
-                                        format!("failed to mkdir {}", parent.display())
-                                    })?;
-                                }
+                if writes || args.restore {
+                    dest::check_name(&file)?;
+                }
+
+                let link = entry_link(&mut metadata, &target.path, &file, &stat)?;
+
+                if link.is_some() && (args.diff || args.restore) {
+                    continue;
+                }
+
+                if let (Some(link), false) = (&link, list || writes || records) {
+                    if args.no_follow {
+                        match link {
+                            Link::Symlink(dest) | Link::Hardlink(dest) => {
+                                writeln!(stdout, "{}", dest)?
                             }
+                        }
+                    } else {
+                        match resolve_link(&file, link, load_metadata(&mut metadata, &target.path)?)
+                        {
+                            Some(dest) if !follow.contains(&dest) => follow.push(dest),
+                            Some(_) => (),
+                            None => writeln!(
+                                stderr(),
+                                "warning: {}: too many levels of symbolic links",
+                                file
+                            )?,
+                        }
+                    }
+                    continue;
+                }
 
-                            let extract_file = File::options()
-                                .write(true)
-                                .create(true)
-                                .truncate(true)
-                                .mode(stat.st_mode)
-                                .open(open_file)
-                                .with_context(|| {
-                                    format!("failed to open {}", open_file.display())
-                                })?;
-
-                            if !exists && Uid::current().is_root() {
-                                fchown(&extract_file, Some(stat.st_uid), Some(stat.st_gid))
-                                    .with_context(|| {
-                                        format!("failed to chown {}", open_file.display())
-                                    })?;
+                if args.restore {
+                    path = format!("{}{}", alpm.root(), file);
+                    output = Output::Restore(Restore::new(&path, stat)?);
+                    state = EntryState::FirstChunk;
+                } else if args.diff {
+                    path = file;
+                    output = Output::Diff(Vec::new());
+                    state = EntryState::FirstChunk;
+                } else if writes && args.dry_run {
+                    path = match (kind, args.install) {
+                        (SFlag::S_IFDIR, _) => dir_path(alpm, args, &file),
+                        (_, true) => match install_path(alpm, args, target, &file)? {
+                            Some((dest, _)) => dest,
+                            None => {
+                                writeln!(stdout, "skip {}{} (NoExtract)", alpm.root(), file)?;
+                                continue;
                             }
+                        },
+                        (_, false) => extract_path(args, target, &file, &mut log.extracted)?,
+                    };
 
-                            output = Output::File(extract_file);
+                    if link.is_none() {
+                        stat.st_mode = write_mode(args, &path, kind, stat.st_mode)?;
+                    }
+
+                    if let Some(desc) = describe_entry(kind, link.as_ref()) {
+                        let existing = Path::new(&path).symlink_metadata().ok();
+                        match existing {
+                            // directories that exist are left as they are
+                            Some(meta) if kind == SFlag::S_IFDIR => {
+                                ensure!(
+                                    meta.is_dir() || meta.is_symlink(),
+                                    "{} exists and is not a directory",
+                                    path
+                                );
+                            }
+                            Some(_) => writeln!(stdout, "overwrite {} ({})", path, desc)?,
+                            None => writeln!(stdout, "create {} ({})", path, desc)?,
+                        }
+                        continue;
+                    }
+
+                    output = Output::Preview(stat, Vec::new());
+                    state = EntryState::FirstChunk;
+                } else if let Some(grep) = &mut ctx.grep {
+                    grep.start(format!("{}:{}", target.name, file));
+                    output = Output::Grep;
+                    state = EntryState::FirstChunk;
+                } else if list || writes || records {
+                    if kind == SFlag::S_IFDIR {
+                        path = dir_path(alpm, args, &file);
+                    } else if args.install {
+                        match install_path(alpm, args, target, &file)? {
+                            Some((dest, pacnew)) => {
+                                if pacnew {
+                                    writeln!(
+                                        stderr(),
+                                        "warning: {}{} installed as {}",
+                                        alpm.root(),
+                                        file,
+                                        dest
+                                    )?;
+                                }
+                                path = dest;
+                            }
+                            None => {
+                                writeln!(
+                                    stderr(),
+                                    "warning: {}{} is in NoExtract, skipping",
+                                    alpm.root(),
+                                    file
+                                )?;
+                                continue;
+                            }
+                        }
+                    } else if args.extract {
+                        path = extract_path(args, target, &file, &mut log.extracted)?;
+                    }
+
+                    if records {
+                        let mut entry =
+                            Record::new(target, &file, &stat, link.as_ref(), args.content);
+                        if writes {
+                            entry.set_dest(&path);
+                        }
+                        match kind == SFlag::S_IFREG && link.is_none() {
+                            true => record = Some(entry),
+                            false => entry.finish(args)?,
                         }
                     } else {
-                        open_output(&mut output, &mut stdout, &filename, use_bat)?;
-                        state = EntryState::FirstChunk;
+                        match describe_entry(kind, link.as_ref()) {
+                            Some(desc) => writeln!(stdout, "{} ({})", file, desc)?,
+                            None => writeln!(stdout, "{}", file)?,
+                        }
+                    }
+
+                    if !writes {
+                        // the content is still read to hash it
+                        if record.is_some() {
+                            state = EntryState::FirstChunk;
+                        }
+                        continue;
                     }
+
+                    if link.is_none() {
+                        stat.st_mode = write_mode(args, &path, kind, stat.st_mode)?;
+                    }
+                    let package = format!("{}-{}", target.name, target.version);
+
+                    if kind == SFlag::S_IFDIR {
+                        let mut meta = None;
+                        if args.preserve.iter().any(|p| p.needs_metadata()) {
+                            meta = load_metadata(&mut metadata, &target.path)?
+                                .get(&file)
+                                .cloned();
+                        }
+                        let root = write_root(alpm, args);
+                        if create_dir(root, &path, &stat, meta.as_ref(), args)? {
+                            if let Some(journal) = &mut log.journal {
+                                journal.record(&path, &package)?;
+                                journal.installed(&path)?;
+                            }
+                        }
+                        log.add_manifest(install_root(alpm, args), &path)?;
+                        continue;
+                    }
+
+                    if let Some(link) = &link {
+                        // hardlinks to files that were not written are skipped
+                        let skip =
+                            matches!(link, Link::Hardlink(t) if !log.written.contains_key(t));
+                        if let (Some(journal), false) = (&mut log.journal, skip) {
+                            journal.record(&path, &package)?;
+                        }
+                        let root = write_root(alpm, args);
+                        if create_link(root, &path, &stat, link, &log.written, args.install)? {
+                            if let Some(journal) = &mut log.journal {
+                                journal.installed(&path)?;
+                            }
+                            log.add_manifest(install_root(alpm, args), &path)?;
+                        }
+                        continue;
+                    }
+
+                    if let Some(journal) = &mut log.journal {
+                        journal.record(&path, &package)?;
+                    }
+                    log.written.insert(file.clone(), path.clone());
+
+                    state = EntryState::FirstChunk;
+                    let root = write_root(alpm, args);
+                    let extract_file = open_install(root, &path, &stat, args.install)?;
+                    output = Output::File(extract_file);
+
+                    if !args.preserve.is_empty() {
+                        let mut meta = None;
+                        if args.preserve.iter().any(|p| p.needs_metadata()) {
+                            meta = load_metadata(&mut metadata, &target.path)?
+                                .get(&file)
+                                .cloned();
+                        }
+                        attrs = Some((stat, meta));
+                    }
+                } else {
+                    if args.headers {
+                        print_header(&mut stdout, ctx, target, &file)?;
+                    }
+                    let prefix = args
+                        .with_filename
+                        .then(|| format!("{}:{}:", target.name, file));
+                    open_output(&mut output, &mut stdout, &filename, use_bat, prefix)?;
+                    state = EntryState::FirstChunk;
                 }
             }
             ArchiveContents::DataChunk(data) if state == EntryState::FirstChunk => {
+                if let Some(record) = &mut record {
+                    record.update(&data);
+                }
                 if is_binary(&data) && matches!(output, Output::Bat(_, _)) {
                     output = Output::Stdout(stdout.lock());
 
                     if args.binary {
-                        read_chunk(&mut state, &mut output, &data)?;
+                        read_chunk(&mut state, &mut output, ctx, &data)?;
                     } else {
                         state = EntryState::Skip;
                         writeln!(
@@ -363,16 +1181,59 @@ where
                         )?;
                     }
                 } else {
-                    read_chunk(&mut state, &mut output, &data)?;
+                    read_chunk(&mut state, &mut output, ctx, &data)?;
                 }
             }
             ArchiveContents::DataChunk(v) if state == EntryState::Reading => {
-                read_chunk(&mut state, &mut output, &v)?;
+                if let Some(record) = &mut record {
+                    record.update(&v);
+                }
+                read_chunk(&mut state, &mut output, ctx, &v)?;
             }
             ArchiveContents::DataChunk(_) => (),
             ArchiveContents::EndOfEntry => {
                 state = EntryState::Skip;
-                close_outout(&mut output)?;
+                if let Output::Diff(data) = &output {
+                    if diff::diff_installed(alpm.root(), &path, data, color)? {
+                        changed += 1;
+                    }
+                    output = Output::None;
+                }
+                if let Output::Preview(stat, data) = &output {
+                    diff::print_preview(&path, stat, data)?;
+                    output = Output::None;
+                }
+                if let Output::Restore(restore) = &mut output {
+                    if let Some(data) = restore.finish()? {
+                        if args.dry_run {
+                            writeln!(stdout, "would restore {}", path)?;
+                        } else {
+                            let mut stat = restore.stat;
+                            stat.st_mode = write_mode(args, &path, SFlag::S_IFREG, stat.st_mode)?;
+                            let mut file = open_install(alpm.root(), &path, &stat, true)?;
+                            file.write_all(&data)
+                                .with_context(|| format!("failed to write {}", path))?;
+                            file.commit()?;
+                            writeln!(stdout, "restored {}", path)?;
+                        }
+                        changed += 1;
+                    }
+                    output = Output::None;
+                }
+                if let (Output::File(file), Some((stat, meta))) = (&output, attrs.take()) {
+                    preserve::apply(file.file(), &path, &stat, meta.as_ref(), &args.preserve)?;
+                }
+                let installed = args.install && matches!(output, Output::File(_));
+                close_outout(&mut output, ctx)?;
+                if let Some(record) = record.take() {
+                    record.finish(args)?;
+                }
+                if installed {
+                    if let Some(journal) = &mut log.journal {
+                        journal.installed(&path)?;
+                    }
+                    log.add_manifest(install_root(alpm, args), &path)?;
+                }
             }
             ArchiveContents::Err(e) => {
                 return Err(e.into());
@@ -380,19 +1241,180 @@ where
         }
     }
 
-    Ok(())
+    // symlinks are printed as the file they point to, which may come earlier in the archive
+    if !follow.is_empty() {
+        let mut links = Match::new(false, follow.clone())?;
+        links.exact_file = true;
+        let archive = open_archive(&target.path)?;
+        changed += dump_files(archive, &mut links, args, alpm, target, log, ctx)?;
+
+        for (i, file) in follow.iter().enumerate() {
+            if !links.matched.contains(&i) {
+                writeln!(
+                    stderr(),
+                    "warning: {} is a symlink to a file not in the package",
+                    file
+                )?;
+            }
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Follow a link within the archive to the path of the file it finally points to
+///
+/// Returns None if the links form a loop.
+fn resolve_link(file: &str, link: &Link, metadata: &HashMap<String, EntryMeta>) -> Option<String> {
+    let mut path = file.to_string();
+    let mut link = link.clone();
+
+    for _ in 0..40 {
+        path = match link {
+            Link::Symlink(dest) => join_link(&path, &dest),
+            Link::Hardlink(dest) => join_link("", &dest),
+        };
+        match metadata.get(&path).and_then(EntryMeta::link) {
+            Some(next) => link = next,
+            None => return Some(path),
+        }
+    }
+
+    None
+}
+
+/// The archive path a symlink at file pointing to dest refers to
+fn join_link(file: &str, dest: &str) -> String {
+    let mut parts = file.split('/').collect::<Vec<_>>();
+    parts.pop();
+    if dest.starts_with('/') {
+        parts.clear();
+    }
+
+    for part in dest.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    parts.join("/")
+}
+
+fn describe_entry(kind: SFlag, link: Option<&Link>) -> Option<String> {
+    match (kind, link) {
+        (_, Some(Link::Symlink(dest))) => Some(format!("symlink to {}", dest)),
+        (_, Some(Link::Hardlink(dest))) => Some(format!("hardlink to {}", dest)),
+        (SFlag::S_IFDIR, _) => Some("directory".to_string()),
+        (SFlag::S_IFLNK, None) => Some("symlink".to_string()),
+        _ => None,
+    }
+}
+
+fn dir_path(alpm: &Alpm, args: &Args, file: &str) -> String {
+    let file = file.trim_end_matches('/');
+    match (args.install, &args.output_dir) {
+        (true, _) => format!("{}{}", install_root(alpm, args), file),
+        (false, Some(dir)) => format!("{}/{}", dir.trim_end_matches('/'), file),
+        (false, None) => file.to_string(),
+    }
+}
+
+/// Create a directory, returning false if it already existed
+fn create_dir(
+    root: &str,
+    path: &str,
+    stat: &stat,
+    meta: Option<&EntryMeta>,
+    args: &Args,
+) -> Result<bool> {
+    let dest = Dest::open(root, path, true)?;
+
+    // leave directories that already exist as they are, including symlinks to them
+    if let Some(existing) = dest.stat()? {
+        if dest::is_type(&existing, SFlag::S_IFDIR) || dest::is_type(&existing, SFlag::S_IFLNK) {
+            return Ok(false);
+        }
+        bail!("{} exists and is not a directory", path);
+    }
+
+    dest.mkdir(stat.st_mode)?;
+    if args.install && Uid::current().is_root() {
+        dest.chown(stat.st_uid, stat.st_gid)?;
+    }
+    if !args.preserve.is_empty() {
+        preserve::apply(&dest.open_dir()?, path, stat, meta, &args.preserve)?;
+    }
+
+    Ok(true)
+}
+
+/// Create a symlink or hardlink, returning false if the target of a hardlink was not written
+fn create_link(
+    root: &str,
+    path: &str,
+    stat: &stat,
+    link: &Link,
+    written: &HashMap<String, String>,
+    install: bool,
+) -> Result<bool> {
+    let dest = Dest::open(root, path, true)?;
+
+    match link {
+        Link::Symlink(target) => {
+            atomic::symlink(&dest, target)?;
+            if install && Uid::current().is_root() {
+                dest.chown(stat.st_uid, stat.st_gid)?;
+            }
+        }
+        Link::Hardlink(target) => match written.get(target) {
+            Some(target) => atomic::hardlink(&dest, &Dest::open(root, target, false)?)?,
+            None => {
+                writeln!(
+                    stderr(),
+                    "warning: {}: hardlink target {} was not written, skipping",
+                    path,
+                    target
+                )?;
+                return Ok(false);
+            }
+        },
+    }
+
+    Ok(true)
 }
 
 fn read_chunk(
     state: &mut EntryState,
     output: &mut Output,
+    ctx: &mut OutputContext,
     data: &[u8],
 ) -> Result<(), anyhow::Error> {
     *state = EntryState::Reading;
     match output {
         Output::Stdout(stdout) => stdout.write_all(data)?,
+        Output::Prefixed(stdout, prefix, started) => {
+            for line in data.split_inclusive(|&b| b == b'\n') {
+                if !*started {
+                    stdout.write_all(prefix.as_bytes())?;
+                }
+                stdout.write_all(line)?;
+                *started = !line.ends_with(b"\n");
+            }
+        }
         Output::Bat(_, stdin) => stdin.write_all(data)?,
         Output::File(file) => file.write_all(data)?,
+        Output::Diff(buf) => buf.extend_from_slice(data),
+        Output::Restore(restore) => restore.update(data)?,
+        Output::Preview(_, buf) => buf.extend_from_slice(data),
+        Output::Grep => {
+            if let Some(grep) = &mut ctx.grep {
+                grep.update(data)?;
+            }
+        }
         Output::None => (),
     };
     Ok(())
@@ -402,21 +1424,37 @@ fn is_binary(data: &[u8]) -> bool {
     data.iter().take(512).any(|&b| b == 0)
 }
 
-fn get_targets(alpm: &Alpm, args: &Args, matcher: &mut Match) -> Result<Vec<String>> {
+fn entry_type(st_mode: u32) -> &'static str {
+    match SFlag::from_bits_truncate(st_mode & SFlag::S_IFMT.bits()) {
+        SFlag::S_IFREG => "file",
+        SFlag::S_IFDIR => "directory",
+        SFlag::S_IFLNK => "symlink",
+        SFlag::S_IFCHR => "character device",
+        SFlag::S_IFBLK => "block device",
+        SFlag::S_IFIFO => "fifo",
+        SFlag::S_IFSOCK => "socket",
+        _ => "unknown",
+    }
+}
+
+fn get_targets(
+    alpm: &Alpm,
+    args: &Args,
+    targets: &[String],
+    matcher: &mut Match,
+) -> Result<Vec<String>> {
     let mut download = Vec::new();
     let mut url = Vec::new();
     let mut repo = Vec::new();
+    let mut local = Vec::new();
     let mut files = Vec::new();
     let dbs = alpm.syncdbs();
 
-    if args.targets.is_empty() {
+    if targets.is_empty() {
         if args.localdb {
             let pkgs = alpm.localdb().pkgs();
-            let pkgs = pkgs
-                .iter()
-                .filter(|pkg| want_pkg(args.all, pkg, matcher))
-                .filter_map(|p| dbs.pkg(p.name()).ok());
-            repo.extend(pkgs);
+            let pkgs = pkgs.iter().filter(|pkg| want_pkg(args.all, pkg, matcher));
+            local.extend(pkgs);
         } else if args.filedb {
             let pkgs = dbs
                 .iter()
@@ -427,11 +1465,16 @@ fn get_targets(alpm: &Alpm, args: &Args, matcher: &mut Match) -> Result<Vec<Stri
 
         if !args.all && !args.executable {
             repo.truncate(1);
+            local.truncate(1);
         }
     } else {
-        for targ in &args.targets {
+        for targ in targets {
             if let Ok(pkg) = get_dbpkg(alpm, targ, args.localdb) {
-                if pkg.files().files().is_empty() || want_pkg(args.all, pkg, matcher) {
+                if args.localdb {
+                    if want_pkg(args.all, pkg, matcher) {
+                        local.push(pkg);
+                    }
+                } else if pkg.files().files().is_empty() || want_pkg(args.all, pkg, matcher) {
                     repo.push(pkg);
                 }
             } else if targ.contains("://") {
@@ -446,6 +1489,22 @@ fn get_targets(alpm: &Alpm, args: &Args, matcher: &mut Match) -> Result<Vec<Stri
 
     matcher.matched.clear();
 
+    let mut installed = Vec::new();
+    for pkg in local {
+        if let Ok(sync) = dbs.pkg(pkg.name()) {
+            if sync.version() != pkg.version() {
+                writeln!(
+                    stderr(),
+                    "warning: using installed {}-{} (repo version is {})",
+                    pkg.name(),
+                    pkg.version(),
+                    sync.version()
+                )?;
+            }
+        }
+        installed.push(get_installed_pkgfile(alpm, pkg)?);
+    }
+
     // todo filter repopkg files
 
     for &pkg in &repo {
@@ -453,7 +1512,37 @@ fn get_targets(alpm: &Alpm, args: &Args, matcher: &mut Match) -> Result<Vec<Stri
     }
     download.extend(url.clone());
 
-    let downloaded = alpm.fetch_pkgurl(download.into_iter())?;
+    if let Some(max) = args.max_total_size {
+        for &pkg in &repo {
+            ensure!(
+                pkg.isize().max(0) as u64 <= max,
+                "{} is larger than {} bytes uncompressed (--max-total-size)",
+                pkg.name(),
+                max
+            );
+        }
+    }
+
+    let downloaded = fetch_packages(alpm, download)?;
+
+    // the size of urls is only known once downloaded and alpm can't abort a transfer from its
+    // callbacks, so this can only check the compressed size afterwards and drop it from the cache
+    if let Some(max) = args.max_total_size {
+        for file in downloaded.iter().skip(repo.len()) {
+            let size = fs::metadata(file)
+                .with_context(|| format!("failed to stat {}", file))?
+                .len();
+            if size > max {
+                let _ = fs::remove_file(file);
+                bail!(
+                    "{} is larger than {} bytes compressed (--max-total-size)",
+                    file,
+                    max
+                );
+            }
+        }
+    }
+
     let mut iter = downloaded.iter();
 
     verify_packages(
@@ -465,11 +1554,12 @@ fn get_targets(alpm: &Alpm, args: &Args, matcher: &mut Match) -> Result<Vec<Stri
     verify_packages(
         alpm,
         alpm.default_siglevel(),
-        iter.by_ref().take(repo.len()),
+        iter.by_ref().take(repo.len()).map(|s| s.as_str()),
     )?;
-    verify_packages(alpm, alpm.remote_file_siglevel(), iter)?;
+    verify_packages(alpm, alpm.remote_file_siglevel(), iter.map(|s| s.as_str()))?;
 
     files.extend(downloaded);
+    files.extend(installed);
 
     Ok(files)
 }
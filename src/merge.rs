@@ -0,0 +1,273 @@
+use crate::args::Args;
+use crate::pacman::{find_owner, get_previous_pkgfile};
+use crate::{diff, open_archive, Match};
+use alpm::Alpm;
+use anyhow::{ensure, Context, Result};
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+    ours: bool,
+}
+
+pub fn merge<W: Write>(alpm: &Alpm, args: &Args, out: &mut W) -> Result<bool> {
+    ensure!(args.files.len() == 1, "--merge takes a single file");
+
+    let file = args.files[0].trim_start_matches('/');
+    let path = format!("{}{}", alpm.root(), file);
+    let pacnew = format!("{}.pacnew", path);
+
+    let pkg = find_owner(alpm, file).with_context(|| format!("no package owns {}", path))?;
+    // the .pacnew is the pristine file of the installed version so the base is the one before
+    let pkgfile = get_previous_pkgfile(alpm, pkg)?;
+
+    let mut matcher = Match::new(false, vec![file.to_string()])?;
+    let mut files = BTreeMap::new();
+    diff::read_files(open_archive(&pkgfile)?, &mut matcher, args, &mut files)?;
+
+    let base = files
+        .get(file)
+        .with_context(|| format!("{} not found in {}", file, pkgfile))?;
+    let ours = fs::read(&path).with_context(|| format!("failed to read {}", path))?;
+    let theirs = fs::read(&pacnew).with_context(|| format!("failed to read {}", pacnew))?;
+
+    let base_label = Path::new(&pkgfile)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split_once(".pkg.tar"))
+        .map_or(pkgfile.as_str(), |(stem, _)| stem);
+    let labels = [path.as_str(), base_label, pacnew.as_str()];
+    let (merged, conflict) = merge3(base, &ours, &theirs, labels);
+
+    out.write_all(&merged)?;
+
+    if conflict {
+        writeln!(io::stderr(), "warning: {} has merge conflicts", path)?;
+    }
+
+    Ok(conflict)
+}
+
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+fn hunks(base: &[&[u8]], side: &[&[u8]], ours: bool) -> Vec<Hunk> {
+    capture_diff_slices(Algorithm::Myers, base, side)
+        .iter()
+        .map(|op| op.as_tag_tuple())
+        .filter(|(tag, _, _)| *tag != DiffTag::Equal)
+        .map(|(_, base, side)| Hunk { base, side, ours })
+        .collect()
+}
+
+fn side_range(region: &[Hunk], ours: bool, base: &Range<usize>) -> Option<Range<usize>> {
+    let mut iter = region.iter().filter(|h| h.ours == ours);
+    let first = iter.next()?;
+    let last = iter.next_back().unwrap_or(first);
+    let start = first.side.start - (first.base.start - base.start);
+    let end = last.side.end + (base.end - last.base.end);
+    Some(start..end)
+}
+
+fn write_marker(out: &mut Vec<u8>, marker: &str, label: &str) {
+    if !out.is_empty() && !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    out.extend_from_slice(marker.as_bytes());
+    if !label.is_empty() {
+        out.push(b' ');
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(b'\n');
+}
+
+fn merge3(base: &[u8], ours: &[u8], theirs: &[u8], labels: [&str; 3]) -> (Vec<u8>, bool) {
+    let base = lines(base);
+    let ours = lines(ours);
+    let theirs = lines(theirs);
+
+    let mut changes = hunks(&base, &ours, true);
+    changes.extend(hunks(&base, &theirs, false));
+    changes.sort_by_key(|h| (h.base.start, h.base.end));
+
+    let mut out = Vec::new();
+    let mut conflict = false;
+    let mut pos = 0;
+    let mut i = 0;
+
+    while i < changes.len() {
+        let mut region = changes[i].base.clone();
+        let mut j = i + 1;
+        while j < changes.len() && changes[j].base.start <= region.end {
+            region.end = region.end.max(changes[j].base.end);
+            j += 1;
+        }
+
+        out.extend(base[pos..region.start].concat());
+
+        let ours_range = side_range(&changes[i..j], true, &region);
+        let theirs_range = side_range(&changes[i..j], false, &region);
+
+        match (ours_range, theirs_range) {
+            (Some(o), None) => out.extend(ours[o].concat()),
+            (None, Some(t)) => out.extend(theirs[t].concat()),
+            (Some(o), Some(t)) if ours[o.clone()] == theirs[t.clone()] => {
+                out.extend(ours[o].concat())
+            }
+            (Some(o), Some(t)) => {
+                conflict = true;
+                write_marker(&mut out, "<<<<<<<", labels[0]);
+                out.extend(ours[o].concat());
+                write_marker(&mut out, "|||||||", labels[1]);
+                out.extend(base[region.clone()].concat());
+                write_marker(&mut out, "=======", "");
+                out.extend(theirs[t].concat());
+                write_marker(&mut out, ">>>>>>>", labels[2]);
+            }
+            (None, None) => unreachable!(),
+        }
+
+        pos = region.end;
+        i = j;
+    }
+
+    out.extend(base[pos..].concat());
+    (out, conflict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alpm::SigLevel;
+    use clap::Parser;
+    use std::fs::create_dir_all;
+
+    const LABELS: [&str; 3] = ["ours", "base", "theirs"];
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+        let (out, conflict) = merge3(base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), LABELS);
+        (String::from_utf8(out).unwrap(), conflict)
+    }
+
+    fn write_pkg(path: &Path, conf: &str) {
+        let mut header = [0u8; 512];
+        header[..12].copy_from_slice(b"etc/foo.conf");
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", conf.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[148..156].fill(b' ');
+        header[156] = b'0';
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        let sum = header.iter().map(|&b| b as u32).sum::<u32>();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+
+        let mut tar = header.to_vec();
+        tar.extend(conf.as_bytes());
+        tar.resize(tar.len().next_multiple_of(512) + 1024, 0);
+        fs::write(path, tar).unwrap();
+    }
+
+    #[test]
+    fn merge_uses_previous_version_as_base() {
+        let dir = std::env::temp_dir().join(format!("paccat-merge-{}", std::process::id()));
+        let root = dir.join("root");
+        let db = dir.join("db");
+        let cache = dir.join("cache");
+        let local = db.join("local").join("foo-2.0-1");
+        create_dir_all(root.join("etc")).unwrap();
+        create_dir_all(&local).unwrap();
+        create_dir_all(&cache).unwrap();
+
+        fs::write(db.join("local").join("ALPM_DB_VERSION"), "9\n").unwrap();
+        fs::write(
+            local.join("desc"),
+            "%NAME%\nfoo\n\n%VERSION%\n2.0-1\n\n%ARCH%\nany\n\n",
+        )
+        .unwrap();
+        fs::write(local.join("files"), "%FILES%\netc/\netc/foo.conf\n\n").unwrap();
+
+        // the installed 2.0-1 created the .pacnew over a file edited under 1.5-1
+        for (version, conf) in [
+            ("1.0-1", "a\nb\nc\n"),
+            ("1.5-1", "a\nb\nc\nd\n"),
+            ("2.0-1", "a\nb\nc\nd\ne\n"),
+        ] {
+            write_pkg(&cache.join(format!("foo-{}-any.pkg.tar", version)), conf);
+        }
+        fs::write(root.join("etc/foo.conf"), "A\nb\nc\nd\n").unwrap();
+        fs::write(root.join("etc/foo.conf.pacnew"), "a\nb\nc\nd\ne\n").unwrap();
+
+        let mut alpm = Alpm::new(root.to_str().unwrap(), db.to_str().unwrap()).unwrap();
+        alpm.add_cachedir(cache.to_str().unwrap()).unwrap();
+        alpm.set_default_siglevel(SigLevel::NONE).unwrap();
+        alpm.set_local_file_siglevel(SigLevel::NONE).unwrap();
+        let args = Args::parse_from(["paccat", "--merge", "--", "/etc/foo.conf"]);
+
+        let mut out = Vec::new();
+        let conflict = super::merge(&alpm, &args, &mut out);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!conflict.unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), "A\nb\nc\nd\ne\n");
+    }
+
+    #[test]
+    fn clean_merge() {
+        let base = "a\nb\nc\nd\ne\n";
+        let ours = "A\nb\nc\nd\ne\n";
+        let theirs = "a\nb\nc\nd\nE\n";
+        assert_eq!(merge(base, ours, theirs), ("A\nb\nc\nd\nE\n".into(), false));
+    }
+
+    #[test]
+    fn unchanged_side_takes_other() {
+        let base = "a\nb\nc\n";
+        let theirs = "a\nB\nc\n";
+        assert_eq!(merge(base, base, theirs), (theirs.into(), false));
+        assert_eq!(merge(base, theirs, base), (theirs.into(), false));
+    }
+
+    #[test]
+    fn same_change_on_both_sides() {
+        let base = "a\nb\nc\n";
+        let both = "a\nB\nc\n";
+        assert_eq!(merge(base, both, both), (both.into(), false));
+    }
+
+    #[test]
+    fn conflict() {
+        let base = "a\nb\nc\n";
+        let ours = "a\nours\nc\n";
+        let theirs = "a\ntheirs\nc\n";
+        let expected =
+            "a\n<<<<<<< ours\nours\n||||||| base\nb\n=======\ntheirs\n>>>>>>> theirs\nc\n";
+        assert_eq!(merge(base, ours, theirs), (expected.into(), true));
+    }
+
+    #[test]
+    fn insert_at_eof() {
+        let base = "a\nb\nc\n";
+        let ours = "A\nb\nc\n";
+        let theirs = "a\nb\nc\nd\n";
+        assert_eq!(merge(base, ours, theirs), ("A\nb\nc\nd\n".into(), false));
+    }
+
+    #[test]
+    fn conflicting_inserts_at_eof_without_newline() {
+        let base = "a\nb";
+        let ours = "a\nb\nours";
+        let theirs = "a\nb\ntheirs";
+        let expected =
+            "a\n<<<<<<< ours\nb\nours\n||||||| base\nb\n=======\nb\ntheirs\n>>>>>>> theirs\n";
+        assert_eq!(merge(base, ours, theirs), (expected.into(), true));
+    }
+}
@@ -1,16 +1,22 @@
+use std::cmp::Ordering;
+use std::ffi::{c_char, c_int, CString};
+use std::fs::{self, read_dir};
 use std::io::{stderr, Write};
+use std::path::Path;
 
-use crate::args::Args;
-use alpm::SigList;
+use crate::args::{Args, Format};
+use crate::format;
+use crate::sandbox;
 use alpm::{
-    Alpm, AnyDownloadEvent, AnyEvent, DownloadEvent, DownloadResult, Event, LogLevel, Package,
-    SigLevel,
+    vercmp, Alpm, AnyDownloadEvent, AnyEvent, DownloadEvent, DownloadResult, Event, LogLevel,
+    Package, SigLevel,
 };
 use alpm_utils::DbListExt;
 use alpm_utils::Targ;
 use anyhow::anyhow;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
 
 pub fn alpm_init(args: &Args) -> Result<Alpm> {
     let mut conf =
@@ -30,12 +36,18 @@ pub fn alpm_init(args: &Args) -> Result<Alpm> {
         alpm.set_dbext(".files");
     }
 
-    alpm.set_dl_cb((), download_cb);
+    alpm.set_dl_cb(args.format, download_cb);
     alpm.set_log_cb((), log_cb);
     alpm.set_event_cb((), event_cb);
 
     alpm_utils::configure_alpm(&mut alpm, &conf)?;
 
+    // when root, downloads run as the sandbox user which can only write to its own directory
+    sandbox::init(args, conf.cache_dir.first().map(|s| s.as_str()))?;
+    if let Some(dir) = sandbox::get().and_then(|s| s.download_dir()) {
+        alpm.add_cachedir(dir)?;
+    }
+
     if let Some(dir) = args.cachedir.as_deref() {
         alpm.add_cachedir(dir)?;
     } else {
@@ -77,6 +89,30 @@ pub fn get_dbpkg<'a>(alpm: &'a Alpm, target_str: &str, localdb: bool) -> Result<
     Ok(pkg)
 }
 
+/// The parts of the .PKGINFO of a package file that are used
+#[derive(Serialize, Deserialize)]
+pub struct PkgInfo {
+    pub name: String,
+    pub version: String,
+    pub backup: Vec<String>,
+}
+
+/// Parse a package file, which should go through sandbox::load_package as it is untrusted
+pub fn load_package(alpm: &Alpm, path: &str) -> Result<PkgInfo> {
+    let pkg = alpm
+        .pkg_load(path, false, SigLevel::empty())
+        .with_context(|| format!("failed to load package {}", path))?;
+    Ok(PkgInfo {
+        name: pkg.name().to_string(),
+        version: pkg.version().to_string(),
+        backup: pkg.backup().iter().map(|b| b.name().to_string()).collect(),
+    })
+}
+
+/// Check the signatures of downloaded packages
+///
+/// gpg can't run in the sandbox, but loading a package with a siglevel makes alpm check the
+/// signature before parsing it, so packages without a valid signature are never parsed as root.
 pub fn verify_packages<'a, I>(alpm: &Alpm, siglevel: SigLevel, files: I) -> Result<()>
 where
     I: IntoIterator<Item = &'a str>,
@@ -85,41 +121,255 @@ where
         return Ok(());
     }
 
-    let mut siglist = SigList::new();
-
     for file in files {
-        if let Err(e) = alpm
-            .pkg_load(file, false, alpm.remote_file_siglevel())?
-            .check_signature(&mut siglist)
-        {
-            if e == alpm::Error::SigMissing && siglevel.contains(SigLevel::PACKAGE_OPTIONAL) {
+        if !Path::new(&format!("{}.sig", file)).exists() {
+            if siglevel.contains(SigLevel::PACKAGE_OPTIONAL) {
                 continue;
             }
-
-            Err(e).with_context(|| format!("failed to verify package {}", file))?;
+            bail!("failed to verify package {}: missing signature", file);
         }
+
+        // SigLevel is not Copy
+        let level = SigLevel::from_bits_retain(siglevel.bits());
+        alpm.pkg_load(file, false, level)
+            .with_context(|| format!("failed to verify package {}", file))?;
     }
 
     Ok(())
 }
 
-pub fn get_download_url(pkg: &Package) -> Result<String> {
+fn get_server(pkg: &Package) -> Result<&str> {
     let server = pkg
         .db()
         .unwrap()
         .servers()
         .first()
         .ok_or(alpm::Error::ServerNone)?;
+    Ok(server)
+}
+
+pub fn get_download_url(pkg: &Package) -> Result<String> {
+    let server = get_server(pkg)?;
     let url = format!("{}/{}", server, pkg.filename().unwrap_or("unknown"));
     Ok(url)
 }
 
-fn download_cb(file: &str, event: AnyDownloadEvent, _: &mut ()) {
+extern "C" {
+    fn fnmatch(pattern: *const c_char, string: *const c_char, flags: c_int) -> c_int;
+}
+
+/// Whether a file is matched by a list of patterns from pacman.conf such as NoExtract
+///
+/// This follows alpm: the last pattern that matches wins and patterns starting with ! mean the
+/// file is not matched.
+pub fn match_patterns<'a, I>(patterns: I, file: &str) -> bool
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let Ok(name) = CString::new(file) else {
+        return false;
+    };
+    let patterns = patterns.into_iter().collect::<Vec<_>>();
+
+    for pattern in patterns.iter().rev() {
+        let (inverted, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.strip_prefix('\\').unwrap_or(pattern)),
+        };
+        let Ok(pattern) = CString::new(pattern) else {
+            continue;
+        };
+        if unsafe { fnmatch(pattern.as_ptr(), name.as_ptr(), 0) } == 0 {
+            return !inverted;
+        }
+    }
+
+    false
+}
+
+pub fn find_owner<'a>(alpm: &'a Alpm, file: &str) -> Option<&'a Package> {
+    alpm.localdb()
+        .pkgs()
+        .iter()
+        .find(|pkg| pkg.files().contains(file).is_some())
+}
+
+pub fn get_installed_pkgfile(alpm: &Alpm, pkg: &Package) -> Result<String> {
+    let arch = pkg.arch().unwrap_or("any");
+    let prefix = format!("{}-{}-{}.pkg.tar", pkg.name(), pkg.version(), arch);
+
+    for (name, path) in cached_pkgfiles(alpm)? {
+        if name.starts_with(&prefix) {
+            verify_packages(alpm, alpm.local_file_siglevel(), [path.as_str()])?;
+            return Ok(path);
+        }
+    }
+
+    download_pkgfile(alpm, pkg.name(), pkg.version().as_str(), arch)
+}
+
+/// Find the package file of the version that was installed before the current one
+///
+/// This is the highest version below the installed one in the cache, or if nothing older is
+/// cached, the version pacman.log says was upgraded from, downloaded from the repository.
+pub fn get_previous_pkgfile(alpm: &Alpm, pkg: &Package) -> Result<String> {
+    let arch = pkg.arch().unwrap_or("any");
+    let installed = pkg.version();
+    let mut previous: Option<(String, String)> = None;
+
+    for (name, path) in cached_pkgfiles(alpm)? {
+        let Some(version) = pkgfile_version(&name, pkg.name(), arch) else {
+            continue;
+        };
+        if vercmp(version, installed.as_str()) != Ordering::Less {
+            continue;
+        }
+        if previous
+            .as_ref()
+            .is_none_or(|(v, _)| vercmp(version, v.as_str()) == Ordering::Greater)
+        {
+            previous = Some((version.to_string(), path));
+        }
+    }
+
+    if let Some((_, path)) = previous {
+        verify_packages(alpm, alpm.local_file_siglevel(), [path.as_str()])?;
+        return Ok(path);
+    }
+
+    let version = alpm
+        .logfile()
+        .and_then(|log| fs::read_to_string(log).ok())
+        .and_then(|log| upgraded_from(&log, pkg.name(), installed.as_str()))
+        .with_context(|| {
+            format!(
+                "no version of {} older than {} is in the cache",
+                pkg.name(),
+                installed
+            )
+        })?;
+
+    download_pkgfile(alpm, pkg.name(), &version, arch)
+}
+
+/// The file names and paths of the package files in the cache
+fn cached_pkgfiles(alpm: &Alpm) -> Result<Vec<(String, String)>> {
+    let mut files = Vec::new();
+
+    for dir in alpm.cachedirs() {
+        let Ok(entries) = read_dir(dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+
+            if name.contains(".pkg.tar") && !name.ends_with(".sig") && !name.ends_with(".part") {
+                let path = Path::new(dir)
+                    .join(name)
+                    .to_str()
+                    .context("cachedir is not a str")?
+                    .to_string();
+                files.push((name.to_string(), path));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// The version of a package file named name-pkgver-pkgrel-arch.pkg.tar.*
+fn pkgfile_version<'a>(file: &'a str, name: &str, arch: &str) -> Option<&'a str> {
+    let (stem, _) = file.split_once(".pkg.tar")?;
+    let version = stem
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix(arch)?
+        .strip_suffix('-')?;
+
+    // pkgver can't contain a '-', so anything else belongs to another package such as name-foo
+    (version.matches('-').count() == 1).then_some(version)
+}
+
+/// The version a package was last upgraded from to reach the installed version
+fn upgraded_from(log: &str, name: &str, installed: &str) -> Option<String> {
+    let prefix = format!("upgraded {} (", name);
+    let suffix = format!(" -> {})", installed);
+
+    log.lines().rev().find_map(|line| {
+        let (_, action) = line.split_once("[ALPM] ")?;
+        let version = action.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+        Some(version.to_string())
+    })
+}
+
+fn download_pkgfile(alpm: &Alpm, name: &str, version: &str, arch: &str) -> Result<String> {
+    let sync = alpm.syncdbs().pkg(name).with_context(|| {
+        format!(
+            "{}-{} is not in the cache and no repository provides {}",
+            name, version, name
+        )
+    })?;
+    let ext = sync
+        .filename()
+        .and_then(|f| f.split_once(".pkg.tar"))
+        .map(|(_, ext)| ext)
+        .unwrap_or(".zst");
+    let url = format!(
+        "{}/{}-{}-{}.pkg.tar{}",
+        get_server(sync)?,
+        name,
+        version,
+        arch,
+        ext
+    );
+
+    let downloaded = fetch_packages(alpm, vec![url]).with_context(|| {
+        format!(
+            "{}-{} is not in the cache and could not be downloaded",
+            name, version
+        )
+    })?;
+    verify_packages(
+        alpm,
+        alpm.default_siglevel(),
+        downloaded.iter().map(|s| s.as_str()),
+    )?;
+
+    let path = downloaded
+        .into_iter()
+        .next()
+        .with_context(|| format!("failed to download {}-{}", name, version))?;
+    Ok(path)
+}
+
+/// Download packages, as the sandbox user when running as root
+pub fn fetch_packages(alpm: &Alpm, urls: Vec<String>) -> Result<Vec<String>> {
+    match sandbox::get() {
+        Some(sandbox) => sandbox.download(alpm, urls),
+        None => Ok(alpm.fetch_pkgurl(urls.into_iter())?.into_iter().collect()),
+    }
+}
+
+fn download_cb(file: &str, event: AnyDownloadEvent, format: &mut Format) {
     if file.ends_with(".sig") {
         return;
     }
 
     if let DownloadEvent::Completed(c) = event.event() {
+        if !matches!(format, Format::Text) {
+            let result = match c.result {
+                DownloadResult::Success => "success",
+                DownloadResult::UpToDate => "up-to-date",
+                DownloadResult::Failed => "failed",
+            };
+            format::event(&format::Event::Download { file, result });
+            return;
+        }
+
         let _ = match c.result {
             DownloadResult::Success => writeln!(stderr(), "{} downloaded", file),
             DownloadResult::UpToDate => writeln!(stderr(), "{} is up to date", file),
@@ -149,3 +399,45 @@ fn event_cb(event: AnyEvent, _: &mut ()) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkgfile_version_of_name() {
+        let file = "foo-1:2.0-1-x86_64.pkg.tar.zst";
+        assert_eq!(pkgfile_version(file, "foo", "x86_64"), Some("1:2.0-1"));
+        assert_eq!(pkgfile_version(file, "foo", "any"), None);
+        assert_eq!(
+            pkgfile_version("foo-bar-2.0-1-any.pkg.tar.zst", "foo", "any"),
+            None
+        );
+    }
+
+    #[test]
+    fn upgraded_from_last_upgrade() {
+        let log = "\
+[2024-01-01T10:00:00+0000] [ALPM] upgraded foo (1.0-1 -> 1.5-1)
+[2024-02-01T10:00:00+0000] [ALPM] upgraded foobar (1.0-1 -> 2.0-1)
+[2024-03-01T10:00:00+0000] [ALPM] upgraded foo (1.5-1 -> 2.0-1)
+[2024-04-01T10:00:00+0000] [ALPM] upgraded foo (2.0-1 -> 2.1-1)
+";
+        assert_eq!(upgraded_from(log, "foo", "2.0-1").as_deref(), Some("1.5-1"));
+        assert_eq!(upgraded_from(log, "foo", "3.0-1"), None);
+    }
+
+    #[test]
+    fn match_patterns_last_wins() {
+        let patterns = ["usr/share/locale/*", "!usr/share/locale/en*"];
+        assert!(match_patterns(patterns, "usr/share/locale/de/foo.mo"));
+        assert!(!match_patterns(patterns, "usr/share/locale/en_GB/foo.mo"));
+        assert!(!match_patterns(patterns, "etc/foo.conf"));
+    }
+
+    #[test]
+    fn match_patterns_inverted_alone_is_not_a_match() {
+        assert!(!match_patterns(["!etc/foo.conf"], "etc/foo.conf"));
+        assert!(match_patterns(["\\!etc"], "!etc"));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::archive::EntryMeta;
+use crate::args::Preserve;
+use anyhow::{Context, Result};
+use nix::libc::{self, stat};
+use nix::unistd::Uid;
+use std::ffi::CString;
+use std::fs::{File, FileTimes, Permissions};
+use std::io;
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn set_xattr(file: &File, path: &str, name: &str, value: &[u8]) -> Result<()> {
+    let cname = CString::new(name).with_context(|| format!("invalid xattr name {}", name))?;
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            cname.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to set xattr {} on {}", name, path));
+    }
+    Ok(())
+}
+
+fn to_time(secs: i64, nsecs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs.max(0) as u64, nsecs.clamp(0, 999_999_999) as u32)
+}
+
+/// Apply the metadata of an archive entry to a file once its content has been written
+///
+/// Ownership is changed first as chown clears setuid bits and capabilities, and timestamps
+/// last as every other change would update them.
+pub fn apply(
+    file: &File,
+    path: &str,
+    stat: &stat,
+    meta: Option<&EntryMeta>,
+    preserve: &[Preserve],
+) -> Result<()> {
+    if preserve.contains(&Preserve::Owner) && Uid::current().is_root() {
+        fchown(file, Some(stat.st_uid), Some(stat.st_gid))
+            .with_context(|| format!("failed to chown {}", path))?;
+    }
+
+    if preserve.contains(&Preserve::Mode) {
+        file.set_permissions(Permissions::from_mode(stat.st_mode & 0o7777))
+            .with_context(|| format!("failed to chmod {}", path))?;
+    }
+
+    if let Some(meta) = meta {
+        if preserve.contains(&Preserve::Xattrs) {
+            // acls are stored as xattrs by some archivers, only restore them with --preserve=acl
+            let xattrs = meta
+                .xattrs
+                .iter()
+                .filter(|(name, _)| !name.starts_with("system.posix_acl_"));
+            for (name, value) in xattrs {
+                set_xattr(file, path, name, value)?;
+            }
+        }
+
+        if preserve.contains(&Preserve::Acl) && !meta.acl_access.is_empty() {
+            set_xattr(file, path, "system.posix_acl_access", &meta.acl_access)?;
+        }
+        // only directories have a default acl
+        if preserve.contains(&Preserve::Acl) && !meta.acl_default.is_empty() {
+            set_xattr(file, path, "system.posix_acl_default", &meta.acl_default)?;
+        }
+    }
+
+    if preserve.contains(&Preserve::Timestamps) {
+        let times = FileTimes::new()
+            .set_accessed(to_time(stat.st_atime, stat.st_atime_nsec))
+            .set_modified(to_time(stat.st_mtime, stat.st_mtime_nsec));
+        file.set_times(times)
+            .with_context(|| format!("failed to set timestamps on {}", path))?;
+    }
+
+    Ok(())
+}
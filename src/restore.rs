@@ -0,0 +1,64 @@
+use crate::compare::Compare;
+use anyhow::{Context, Result};
+use nix::libc::stat;
+use std::fs::File;
+use std::io::ErrorKind;
+
+pub struct Restore {
+    pub stat: stat,
+    path: String,
+    compare: Compare,
+    data: Vec<u8>,
+}
+
+impl Restore {
+    pub fn new(path: &str, stat: stat) -> Result<Self> {
+        let installed = match File::open(path) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("failed to open {}", path)),
+        };
+
+        let compare = Compare::new(installed, stat.st_size.max(0) as u64)
+            .with_context(|| format!("failed to stat {}", path))?;
+
+        Ok(Self {
+            stat,
+            path: path.to_string(),
+            compare,
+            data: Vec::new(),
+        })
+    }
+
+    /// Package data is only kept once it differs from the installed file
+    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+        if self.compare.same() && !self.compare.update(data) {
+            self.data = self
+                .compare
+                .matched()
+                .with_context(|| format!("failed to read {}", self.path))?;
+        }
+
+        if !self.compare.same() {
+            self.data.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    /// Returns the package content if it differs from the installed file
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.compare.same() {
+            return Ok(Some(std::mem::take(&mut self.data)));
+        }
+        if self.compare.finish() {
+            return Ok(None);
+        }
+
+        // the installed file only has extra data at the end so all of the entry matched
+        let data = self
+            .compare
+            .matched()
+            .with_context(|| format!("failed to read {}", self.path))?;
+        Ok(Some(data))
+    }
+}
@@ -0,0 +1,510 @@
+use crate::archive::{self, EntryMeta};
+use crate::args::Args;
+use crate::pacman::{self, PkgInfo};
+use alpm::Alpm;
+use anyhow::{anyhow, bail, Context, Result};
+use compress_tools::{ArchiveContents, ArchiveIterator};
+use nix::libc::{self, stat};
+use nix::sys::prctl::set_no_new_privs;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{chown, fork, pipe, setgid, setgroups, setuid, ForkResult, Gid, Pid};
+use nix::unistd::{Uid, User};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::OnceLock;
+
+static SANDBOX: OnceLock<Option<Sandbox>> = OnceLock::new();
+
+// no single entry name or data chunk from the parser should come close to this
+const MAX_FRAME: u64 = 64 * 1024 * 1024;
+
+/// An unprivileged user that downloads and parses packages when paccat runs as root
+///
+/// The work is done in a forked child that drops to the user and is confined with landlock,
+/// so only the final writes of --install run privileged.
+pub struct Sandbox {
+    uid: Uid,
+    gid: Gid,
+    download_dir: Option<PathBuf>,
+}
+
+/// Set up the sandbox if running as root, cachedir is where downloads end up
+pub fn init(args: &Args, cachedir: Option<&str>) -> Result<()> {
+    let sandbox = match Uid::effective().is_root() {
+        true => sandbox_user(args)?.map(|(uid, gid)| Sandbox {
+            uid,
+            gid,
+            download_dir: cachedir
+                .map(|dir| Path::new(dir).join(format!("download-paccat-{}", std::process::id()))),
+        }),
+        false => None,
+    };
+    let _ = SANDBOX.set(sandbox);
+    Ok(())
+}
+
+pub fn get() -> Option<&'static Sandbox> {
+    SANDBOX.get().and_then(|s| s.as_ref())
+}
+
+fn sandbox_user(args: &Args) -> Result<Option<(Uid, Gid)>> {
+    if let Some(name) = &args.download_user {
+        let user = User::from_name(name)?.with_context(|| format!("unknown user {}", name))?;
+        return Ok(Some((user.uid, user.gid)));
+    }
+
+    let sudo = env::var("SUDO_UID").ok().zip(env::var("SUDO_GID").ok());
+    if let Some((uid, gid)) = sudo.and_then(|(u, g)| u.parse().ok().zip(g.parse().ok())) {
+        if uid != 0 {
+            return Ok(Some((Uid::from_raw(uid), Gid::from_raw(gid))));
+        }
+    }
+
+    Ok(User::from_name("nobody")?.map(|user| (user.uid, user.gid)))
+}
+
+/// Read the link targets and extended attributes of a package, sandboxed if running as root
+pub fn read_metadata(path: &str) -> Result<HashMap<String, EntryMeta>> {
+    match get() {
+        Some(sandbox) => sandbox.run(&[Path::new(path)], &[], true, || {
+            archive::read_metadata(path)
+        }),
+        None => archive::read_metadata(path),
+    }
+}
+
+/// Read the name, version and backup files of a package file, sandboxed if running as root
+pub fn load_package(alpm: &Alpm, path: &str) -> Result<PkgInfo> {
+    match get() {
+        Some(sandbox) => sandbox.run(&[Path::new(path)], &[], true, || {
+            pacman::load_package(alpm, path)
+        }),
+        None => pacman::load_package(alpm, path),
+    }
+}
+
+impl Sandbox {
+    /// The directory the download user writes to, added as a cachedir so alpm downloads there
+    pub fn download_dir(&self) -> Option<&str> {
+        self.download_dir.as_deref().and_then(Path::to_str)
+    }
+
+    /// Download packages as the sandbox user and move them into the cache
+    pub fn download(&self, alpm: &Alpm, urls: Vec<String>) -> Result<Vec<String>> {
+        let Some(dir) = &self.download_dir else {
+            return Ok(alpm.fetch_pkgurl(urls.into_iter())?.into_iter().collect());
+        };
+
+        // left behind if a previous paccat with the same pid was killed
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).with_context(|| format!("failed to mkdir {}", dir.display()))?;
+        chown(dir, Some(self.uid), Some(self.gid))
+            .with_context(|| format!("failed to chown {}", dir.display()))?;
+
+        let res = self.download_to(alpm, dir, urls);
+        let _ = fs::remove_dir_all(dir);
+        res
+    }
+
+    fn download_to(&self, alpm: &Alpm, dir: &Path, urls: Vec<String>) -> Result<Vec<String>> {
+        let downloaded = self.run(&[Path::new("/")], &[dir], false, || {
+            let downloaded = alpm.fetch_pkgurl(urls.into_iter())?;
+            Ok(downloaded.into_iter().collect::<Vec<String>>())
+        })?;
+
+        let cachedir = dir.parent().context("download dir has no parent")?;
+        let mut files = Vec::new();
+
+        for file in downloaded {
+            let path = Path::new(&file);
+            let Some(name) = path.file_name().filter(|_| path.parent() == Some(dir)) else {
+                // already in the cache
+                files.push(file);
+                continue;
+            };
+
+            let dest = cachedir.join(name);
+            take_file(path, &dest)?;
+            let sig = path.with_file_name(format!("{}.sig", name.to_string_lossy()));
+            if sig.exists() {
+                take_file(&sig, &dest.with_file_name(sig.file_name().unwrap()))?;
+            }
+
+            let dest = dest.to_str().context("cachedir is not a str")?;
+            files.push(dest.to_string());
+        }
+
+        Ok(files)
+    }
+
+    /// Parse an archive in a sandboxed child, streaming its entries back
+    pub fn open_archive(&self, file: File) -> Result<Entries> {
+        let (read, write) = pipe()?;
+
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(read);
+                let mut out = BufWriter::new(File::from(write));
+                let keep = [file.as_raw_fd(), out.get_ref().as_raw_fd()];
+                let res = self
+                    .enter(&[], &[], Some(&keep))
+                    .and_then(|()| stream(file, &mut out));
+                if let Err(e) = res {
+                    let _ = write_frame(&mut out, b'X', format!("{:#}", e).as_bytes());
+                }
+                let _ = out.flush();
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                drop(write);
+                Ok(Entries {
+                    reader: BufReader::new(File::from(read)),
+                    child,
+                    done: false,
+                })
+            }
+        }
+    }
+
+    /// Run f in a sandboxed child that may only read the read paths and write the write paths
+    ///
+    /// With isolate set every inherited file descriptor other than stdio is closed too.
+    fn run<T, F>(&self, read: &[&Path], write: &[&Path], isolate: bool, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<T>,
+    {
+        let (reader, writer) = pipe()?;
+
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(reader);
+                let mut out = File::from(writer);
+                let keep = [out.as_raw_fd()];
+                let res = self
+                    .enter(read, write, isolate.then_some(&keep[..]))
+                    .and_then(|()| f())
+                    .map_err(|e| format!("{:#}", e));
+                let ok = serde_json::to_writer(&mut out, &res).is_ok();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                drop(writer);
+                let mut buf = Vec::new();
+                let res = File::from(reader).read_to_end(&mut buf);
+                let status = waitpid(child, None)?;
+                res.context("failed to read from the sandbox")?;
+
+                match serde_json::from_slice::<Result<T, String>>(&buf) {
+                    Ok(res) => res.map_err(|e| anyhow!(e)),
+                    Err(_) => bail!("sandboxed process failed ({:?})", status),
+                }
+            }
+        }
+    }
+
+    /// Drop privileges and confine the current process, which must be a forked child
+    fn enter(&self, read: &[&Path], write: &[&Path], keep: Option<&[RawFd]>) -> Result<()> {
+        if let Some(keep) = keep {
+            close_fds(keep)?;
+        }
+
+        // rules have to be opened before dropping privileges in case the user can't reach them
+        let rules = landlock::Rules::new(read, write)?;
+
+        setgroups(&[self.gid]).context("failed to drop supplementary groups")?;
+        setgid(self.gid).context("failed to drop group")?;
+        setuid(self.uid).context("failed to drop user")?;
+        set_no_new_privs().context("failed to set no_new_privs")?;
+
+        rules.restrict()
+    }
+}
+
+/// Move a file written by the sandbox user into the cache and make it owned by root
+fn take_file(src: &Path, dest: &Path) -> Result<()> {
+    fs::rename(src, dest).with_context(|| format!("failed to move {}", src.display()))?;
+
+    // the user can no longer touch the file once it is in the cache so check it now
+    let meta = fs::symlink_metadata(dest)?;
+    if !meta.is_file() || meta.nlink() != 1 {
+        let _ = fs::remove_file(dest);
+        bail!("downloaded file {} is not a regular file", dest.display());
+    }
+    lchown(dest, Some(0), Some(0))
+        .with_context(|| format!("failed to chown {}", dest.display()))?;
+    Ok(())
+}
+
+fn close_fds(keep: &[RawFd]) -> Result<()> {
+    let fds = fs::read_dir("/proc/self/fd")
+        .context("failed to list open files")?
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse::<RawFd>().ok())
+        .filter(|fd| *fd > 2 && !keep.contains(fd))
+        .collect::<Vec<_>>();
+
+    for fd in fds {
+        unsafe { libc::close(fd) };
+    }
+    Ok(())
+}
+
+fn write_frame(out: &mut impl Write, tag: u8, data: &[u8]) -> io::Result<()> {
+    out.write_all(&[tag])?;
+    out.write_all(&(data.len() as u64).to_le_bytes())?;
+    out.write_all(data)
+}
+
+fn stat_bytes(stat: &stat) -> &[u8] {
+    unsafe { slice::from_raw_parts((stat as *const stat).cast(), mem::size_of::<stat>()) }
+}
+
+/// Parse the archive and write each entry to the parent, ended by Z once the archive is done
+fn stream(file: File, out: &mut impl Write) -> Result<()> {
+    for content in ArchiveIterator::from_read(file)? {
+        match content {
+            ArchiveContents::StartOfEntry(name, stat) => {
+                write_frame(out, b'S', name.as_bytes())?;
+                out.write_all(stat_bytes(&stat))?;
+            }
+            ArchiveContents::DataChunk(data) => write_frame(out, b'D', &data)?,
+            ArchiveContents::EndOfEntry => out.write_all(b"E")?,
+            ArchiveContents::Err(e) => return Err(e.into()),
+        }
+    }
+    out.write_all(b"Z")?;
+    Ok(())
+}
+
+/// The entries of an archive parsed by a sandboxed child
+///
+/// Everything the child sends is treated as untrusted, the same as the archive itself.
+pub struct Entries {
+    reader: BufReader<File>,
+    child: Pid,
+    done: bool,
+}
+
+impl Entries {
+    fn read_data(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0; 8];
+        self.reader.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > MAX_FRAME {
+            return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+        }
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<ArchiveContents>> {
+        let mut tag = [0];
+        self.reader.read_exact(&mut tag)?;
+
+        let content = match tag[0] {
+            b'S' => {
+                let name = String::from_utf8(self.read_data()?)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                let mut stat = unsafe { mem::zeroed::<stat>() };
+                let buf = unsafe {
+                    slice::from_raw_parts_mut(
+                        (&mut stat as *mut stat).cast(),
+                        mem::size_of::<stat>(),
+                    )
+                };
+                self.reader.read_exact(buf)?;
+                ArchiveContents::StartOfEntry(name, stat)
+            }
+            b'D' => ArchiveContents::DataChunk(self.read_data()?),
+            b'E' => ArchiveContents::EndOfEntry,
+            b'X' => {
+                let msg = String::from_utf8_lossy(&self.read_data()?).into_owned();
+                self.done = true;
+                ArchiveContents::Err(io::Error::other(msg).into())
+            }
+            b'Z' => return Ok(None),
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "invalid frame")),
+        };
+        Ok(Some(content))
+    }
+}
+
+impl Iterator for Entries {
+    type Item = ArchiveContents;
+
+    fn next(&mut self) -> Option<ArchiveContents> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_frame() {
+            Ok(Some(content)) => Some(content),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                let msg = match e.kind() {
+                    ErrorKind::UnexpectedEof => "archive parser exited unexpectedly".to_string(),
+                    _ => format!("failed to read from archive parser: {}", e),
+                };
+                Some(ArchiveContents::Err(io::Error::other(msg).into()))
+            }
+        }
+    }
+}
+
+impl Drop for Entries {
+    fn drop(&mut self) {
+        // the child may be blocked writing entries that will never be read
+        let _ = kill(self.child, Signal::SIGKILL);
+        let _ = waitpid(self.child, None);
+    }
+}
+
+mod landlock {
+    use anyhow::{Context, Result};
+    use nix::fcntl::{open, OFlag};
+    use nix::libc;
+    use nix::sys::stat::Mode;
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::path::Path;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    // every right in the first abi, later abis add REFER and TRUNCATE
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// A landlock ruleset denying all filesystem access other than the given paths
+    ///
+    /// Kernels without landlock are left unconfined other than by the dropped privileges.
+    pub struct Rules {
+        ruleset: Option<OwnedFd>,
+    }
+
+    impl Rules {
+        pub fn new(read: &[&Path], write: &[&Path]) -> Result<Self> {
+            let abi = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    std::ptr::null::<RulesetAttr>(),
+                    0,
+                    CREATE_RULESET_VERSION,
+                )
+            };
+            if abi < 1 {
+                return Ok(Self { ruleset: None });
+            }
+
+            let mut handled = ACCESS_FS_V1;
+            if abi >= 2 {
+                handled |= ACCESS_FS_REFER;
+            }
+            if abi >= 3 {
+                handled |= ACCESS_FS_TRUNCATE;
+            }
+
+            let attr = RulesetAttr {
+                handled_access_fs: handled,
+            };
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    &attr as *const RulesetAttr,
+                    mem::size_of::<RulesetAttr>(),
+                    0,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error())
+                    .context("failed to create landlock ruleset");
+            }
+            let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+            for path in read {
+                let access = match path.is_dir() {
+                    true => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+                    false => ACCESS_FS_READ_FILE,
+                };
+                add_rule(&ruleset, path, access)?;
+            }
+            for path in write {
+                add_rule(&ruleset, path, handled & !ACCESS_FS_EXECUTE)?;
+            }
+
+            Ok(Self {
+                ruleset: Some(ruleset),
+            })
+        }
+
+        pub fn restrict(self) -> Result<()> {
+            let Some(ruleset) = self.ruleset else {
+                return Ok(());
+            };
+            let ret =
+                unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error())
+                    .context("failed to enforce landlock ruleset");
+            }
+            Ok(())
+        }
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+        let fd = open(path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty())
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to add landlock rule for {}", path.display()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use crate::args::Args;
+use crate::pacman::{fetch_packages, get_download_url, verify_packages};
+use crate::sandbox;
+use crate::{dump_files, open_archive, InstallLog, Match, OutputContext, Target};
+use alpm::Alpm;
+use alpm_utils::DbListExt;
+use anyhow::Result;
+use std::io::{self, Write};
+
+pub fn upgrade_preview(alpm: &Alpm, args: &Args, color: bool) -> Result<bool> {
+    let dbs = alpm.syncdbs();
+    let mut upgrades = Vec::new();
+    let mut differ = false;
+
+    for local in alpm.localdb().pkgs() {
+        if !args.targets.is_empty() && !args.targets.iter().any(|t| t == local.name()) {
+            continue;
+        }
+        if let Ok(sync) = dbs.pkg(local.name()) {
+            if sync.version() > local.version() {
+                upgrades.push((local, sync));
+            }
+        }
+    }
+
+    if upgrades.is_empty() {
+        return Ok(false);
+    }
+
+    let mut download = Vec::new();
+    for (_, sync) in &upgrades {
+        download.push(get_download_url(sync)?);
+    }
+
+    let downloaded = fetch_packages(alpm, download)?;
+    verify_packages(
+        alpm,
+        alpm.default_siglevel(),
+        downloaded.iter().map(|s| s.as_str()),
+    )?;
+
+    let ctx = &mut OutputContext {
+        color,
+        grep: None,
+        header: false,
+    };
+
+    for ((local, sync), file) in upgrades.iter().zip(downloaded.iter()) {
+        let backup = sandbox::load_package(alpm, file)?.backup;
+
+        if backup.is_empty() {
+            continue;
+        }
+
+        writeln!(
+            io::stdout(),
+            ":: {} {} -> {}",
+            local.name(),
+            local.version(),
+            sync.version()
+        )?;
+
+        let mut matcher = Match::new(false, backup)?;
+        let archive = open_archive(file)?;
+        let target = Target {
+            path: file.to_string(),
+            ..Default::default()
+        };
+        let log = &mut InstallLog::default();
+        differ |= dump_files(archive, &mut matcher, args, alpm, &target, log, ctx)? > 0;
+    }
+
+    Ok(differ)
+}